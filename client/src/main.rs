@@ -1,18 +1,86 @@
-use std::{
-    fs,
-    io::{BufRead, BufReader, Read, Write},
-    path::PathBuf,
-    vec,
-};
+use std::{collections::HashMap, fs, io::IsTerminal, path::PathBuf};
 
-use clap::{arg, command, value_parser, ArgAction, Command};
+use cbak_config::{_DirConfig, Severity, VcsKind, WatcherKind, GLOBAL_KEYS, WATCH_KEYS};
+use cbak_proto::{ChangeKind, Client, Request, Response};
+use clap::{
+    arg, builder::PossibleValuesParser, builder::TypedValueParser, command, value_parser,
+    ArgAction, Command,
+};
 use cli_table::{format::Justify, print_stdout, Cell, CellStruct, Style, Table};
-use interprocess::local_socket::{LocalSocketStream, NameTypeSupport};
 
-mod config;
+fn connect(sock_name: &str) -> Client {
+    match Client::connect(sock_name) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Could not connect to cbakd on {}: {}", sock_name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Sends a single request to the daemon, exiting with an error message if it fails
+fn request(sock_name: &str, req: Request) -> Response {
+    match connect(sock_name).request(&req) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn unexpected(res: Response) -> ! {
+    eprintln!("Unexpected response from cbakd: {:?}", res);
+    std::process::exit(1);
+}
+
+/// Formats an RFC 3339 time from the daemon in local time
+fn local_time(time: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|_| time.to_string())
+}
+
+/// Parses `pause --for` into whole seconds, the daemon resumes a watch no earlier than asked
+fn pause_seconds(s: &str) -> Result<u64, String> {
+    let d = humantime::parse_duration(s).map_err(|e| e.to_string())?;
+    if d.as_secs() == 0 {
+        return Err("has to be at least 1s".to_string());
+    }
+    Ok(d.as_secs() + (d.subsec_nanos() > 0) as u64)
+}
+
+/// Colors one line of a unified diff like git does
+fn colorize(line: &str) -> String {
+    let color = if line.starts_with("diff ")
+        || line.starts_with("index ")
+        || line.starts_with("--- ")
+        || line.starts_with("+++ ")
+    {
+        "1"
+    } else if line.starts_with("@@") {
+        "36"
+    } else if line.starts_with('+') {
+        "32"
+    } else if line.starts_with('-') {
+        "31"
+    } else {
+        return line.to_string();
+    };
+    format!("\x1b[{}m{}\x1b[0m", color, line)
+}
 
 fn main() {
     let matches = command!()
+        .arg(
+            arg!(-s --socket <NAME> "Socket cbakd listens on, if it was started with --socket")
+                .required(false)
+                .global(true),
+        )
         .subcommand(
             Command::new("watch")
                 .about("Add a directory to the watchlist")
@@ -36,6 +104,32 @@ fn main() {
                         .required(false)
                         .value_parser(value_parser!(i32)),
                 )
+                .arg(
+                    arg!(--watcher <WATCHER> "How to detect changes")
+                        .required(false)
+                        .value_parser(
+                            PossibleValuesParser::new(WatcherKind::NAMES)
+                                .map(|s| s.parse::<WatcherKind>().unwrap()),
+                        ),
+                )
+                .arg(
+                    arg!(--vcs <BACKEND> "Git implementation to commit with")
+                        .required(false)
+                        .value_parser(
+                            PossibleValuesParser::new(VcsKind::NAMES)
+                                .map(|s| s.parse::<VcsKind>().unwrap()),
+                        ),
+                )
+                .arg(
+                    arg!(-m --"commit-message" <TEMPLATE> "Commit message template, e.g. \"{name}: {summary}\"")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--"git-dir" <GIT_DIR> "Where to keep the repository, defaults to <data_dir>/<NAME>.git")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
                 .arg(arg!([IGNORE]... "Set of regexs of directorys to ingore").required(false)),
         )
         .subcommand(
@@ -55,6 +149,11 @@ fn main() {
         .subcommand(
             Command::new("edit-config")
                 .about("Edit the configuration")
+                .after_help(format!(
+                    "SCOPE is either global or the name of a watch.\nGlobal keys: {}\nWatch keys: {}",
+                    GLOBAL_KEYS.join(", "),
+                    WATCH_KEYS.join(", ")
+                ))
                 .arg(
                     arg!(<SCOPE> "Configuration scope")
                         .required(true)
@@ -68,325 +167,312 @@ fn main() {
                         .action(ArgAction::SetTrue),
                 ),
         )
+        .subcommand(
+            Command::new("config")
+                .about("Work with the configuration file")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Check a config file, the one cbakd uses without PATH")
+                        .arg(
+                            arg!([PATH] "Config file to check")
+                                .required(false)
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(--json "Print the problems as JSON")
+                                .required(false)
+                                .action(ArgAction::SetTrue),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("log")
+                .about("Show the commits of a watch")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(arg!([PATH] "Only commits touching this file or directory, relative to the watched directory").required(false))
+                .arg(
+                    arg!(--since <TIME> "Only commits after this time, e.g. \"2022-10-20 14:00\"")
+                        .required(false),
+                )
+                .arg(arg!(--until <TIME> "Only commits before this time").required(false))
+                .arg(
+                    arg!(-n --"max-count" <COUNT> "Show at most this many commits")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--json "Print the commits as JSON")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Show what changed in a watch, by default since the last commit")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!([REV] "Revision or time to compare from & to, e.g. HEAD~2 or \"1 hour ago\". Without a second one the files on disk are used")
+                        .required(false)
+                        .num_args(0..=2),
+                )
+                .arg(arg!([PATH] "Only diff this file or directory").required(false).last(true)),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Put files of a watch back the way they were at an earlier revision")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(arg!(<REV> "Revision, tag or time to restore, e.g. HEAD~1 or \"1 hour ago\"").required(true))
+                .arg(arg!([PATH]... "Files or directories to restore, everything if none are given").required(false))
+                .arg(
+                    arg!(--"dry-run" "Only show what would change")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("show")
+                .about("Print a file as it was at a revision or time")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(arg!(<REV> "Revision, tag or time, e.g. HEAD~1 or \"3 days ago\"").required(true))
+                .arg(arg!(<PATH> "File to print, relative to the watched directory").required(true)),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show whether the workers are running & what they last did")
+                .arg(arg!([NAME] "Only show this watch").required(false))
+                .arg(
+                    arg!(--json "Print the status as JSON")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("pause")
+                .about("Stop committing changes of a watch until it is resumed")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--for <DURATION> "Resume on its own after this long, e.g. 30min or 2h")
+                        .required(false)
+                        .value_parser(pause_seconds),
+                ),
+        )
+        .subcommand(
+            Command::new("resume")
+                .about("Continue committing changes of a paused watch")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--"no-commit" "Don't commit what changed while paused right away")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("commit")
+                .about("Commit the current state of a watch right away")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(arg!(-m --message <MESSAGE> "Commit message").required(true))
+                .arg(arg!(--author <AUTHOR> "Author of the commit, as \"Name <email>\"").required(false)),
+        )
+        .subcommand(
+            Command::new("tag")
+                .about("Commit pending changes & tag the result, e.g. before an upgrade")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(arg!(<TAG> "Name of the tag").required(true))
+                .arg(arg!(-m --message <MESSAGE> "Tag message, defaults to the tag name").required(false)),
+        )
+        .subcommand(
+            Command::new("tags")
+                .about("List the tags of a watch")
+                .arg(
+                    arg!(<NAME> "Name of the watch entry")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--json "Print the tags as JSON")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
         .get_matches();
 
-    let sock_name = {
-        use NameTypeSupport::*;
-        match NameTypeSupport::query() {
-            OnlyPaths => "/tmp/cbak.sock",
-            OnlyNamespaced | Both => "@cbak.sock",
-        }
-    };
+    let sock_name = matches
+        .get_one::<String>("socket")
+        .map(String::as_str)
+        .unwrap_or(cbak_proto::socket_name());
 
     match matches.subcommand() {
         Some(("reload", _)) => {
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            conn.get_mut()
-                .write_all(&[0b0000_0010, 0xA])
-                .expect("write failure");
+            request(sock_name, Request::Reload);
         }
         Some(("watch", args)) => {
             let directory = args.get_one::<PathBuf>("DIRECTORY").unwrap();
             let name = args.get_one::<String>("NAME").unwrap();
-            let poll_interval = args.get_one::<i32>("poll-interval");
-            let write_delay = args.get_one::<i32>("write-delay");
             let ignore = args.get_many::<String>("IGNORE");
 
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            let mut buf = String::new();
-            conn.get_mut().write_all(&[0b0000_0100, 0xA]).unwrap();
-            conn.read_line(&mut buf).unwrap();
-            buf.pop();
-
-            let conf_file_path = buf;
-            let mut conf_file =
-                fs::File::open(&conf_file_path).expect("Failed to open config file");
-
-            let mut buf = String::new();
-            conf_file
-                .read_to_string(&mut buf)
-                .expect("Failed to open config file");
-            let mut conf = config::CbakConfig::new(&buf);
-
-            conf.watch.push(config::_DirConfig {
-                directory: fs::canonicalize(directory)
-                    .expect("Not a valid directory")
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-                ignore: if let Some(n) = ignore {
-                    n.map(|x| x.to_string()).collect()
-                } else {
-                    vec![]
+            let directory = match fs::canonicalize(directory) {
+                Ok(d) => d.to_string_lossy().into_owned(),
+                Err(e) => {
+                    eprintln!("Not a valid directory: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            request(
+                sock_name,
+                Request::AddWatch {
+                    watch: _DirConfig {
+                        name: Some(name.to_owned()),
+                        directory,
+                        ignore: if let Some(n) = ignore {
+                            n.map(|x| x.to_string()).collect()
+                        } else {
+                            vec![]
+                        },
+                        poll_interval: args.get_one::<i32>("poll-interval").copied(),
+                        write_delay: args.get_one::<i32>("write-delay").copied(),
+                        watcher: args.get_one::<WatcherKind>("watcher").copied(),
+                        vcs: args.get_one::<VcsKind>("vcs").copied(),
+                        commit_message: args.get_one::<String>("commit-message").cloned(),
+                        git_dir: args
+                            .get_one::<PathBuf>("git-dir")
+                            .map(|n| n.to_string_lossy().into_owned()),
+                    },
                 },
-                poll_interval: poll_interval.map(|n| n.to_owned()),
-                write_delay: write_delay.map(|n| n.to_owned()),
-                name: name.to_owned(),
-            });
-
-            let updated_conf = toml::to_string(&conf).unwrap();
-
-            fs::copy(&conf_file_path, format!("{}.bak", &conf_file_path)).unwrap();
-            fs::write(&conf_file_path, updated_conf).unwrap();
-
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            conn.get_mut()
-                .write_all(&[0b0000_0010, 0xA])
-                .expect("write failure");
+            );
         }
         Some(("edit-config", args)) => {
-            let scope = args.get_one::<String>("SCOPE").unwrap();
-            let key = args.get_one::<String>("KEY");
-            let value = args.get_many::<String>("VALUE");
+            let scope = args.get_one::<String>("SCOPE").unwrap().to_owned();
+            let key = args.get_one::<String>("KEY").cloned();
+            let value = args
+                .get_many::<String>("VALUE")
+                .map(|v| v.cloned().collect::<Vec<String>>());
             let no_reload = args.get_flag("no-reload");
 
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            let mut buf = String::new();
-            conn.get_mut().write_all(&[0b0000_0100, 0xA]).unwrap();
-            conn.read_line(&mut buf).unwrap();
-            buf.pop();
-
-            let conf_file_path = buf;
-            let mut conf_file =
-                fs::File::open(&conf_file_path).expect("Failed to open config file");
-
-            let mut buf = String::new();
-            conf_file
-                .read_to_string(&mut buf)
-                .expect("Failed to open config file");
-            let mut conf = config::CbakConfig::new(&buf);
-
-            match scope.as_str() {
-                "global" => {
-                    if key.is_none() & value.is_none() {
-                        println!("{:?}", conf.global);
-                        return;
-                    }
-
-                    if value.is_none() {
-                        match key.unwrap().as_str() {
-                            "poll_interval" => {
-                                println!("{:?}", conf.global.poll_interval);
-                            }
-                            "write_delay" => {
-                                println!("{:?}", conf.global.write_delay);
-                            }
-                            "ignore" => {
-                                println!("{:?}", conf.global.ignore);
-                            }
-                            _ => {
-                                eprintln!("Invalid key");
-                            }
-                        }
-                        return;
-                    }
-
-                    //let value = value.unwrap().collect::<Vec<&String>>();
-                    match key.unwrap().as_str() {
-                        "poll_interval" => {
-                            let v = value.unwrap().collect::<Vec<&String>>();
-                            if v.len() != 1 {
-                                eprintln!("Invalid number of arguments");
-                                return;
-                            }
-
-                            if let Ok(n) = v[0].parse::<i32>() {
-                                if n.is_negative() {
-                                    eprintln!("Expected positive integer");
-                                    return;
-                                }
-                                conf.global.poll_interval = n;
-                            } else {
-                                eprintln!("Expected positive integer");
-                                return;
-                            }
-                        }
-                        "write_delay" => {
-                            let v = value.unwrap().collect::<Vec<&String>>();
-                            if v.len() != 1 {
-                                eprintln!("Invalid number of arguments");
-                                return;
-                            }
-
-                            if let Ok(n) = v[0].parse::<i32>() {
-                                if n.is_negative() {
-                                    eprintln!("Expected positive integer");
-                                    return;
-                                }
-                                conf.global.write_delay = n;
-                            } else {
-                                eprintln!("Expected positive integer");
-                                return;
-                            }
-                        }
-                        "ignore" => {
-                            conf.global.ignore = if let Some(n) = value {
-                                n.collect::<Vec<&String>>()
-                                    .iter()
-                                    .map(|d| d.to_string())
-                                    .collect::<Vec<String>>()
-                            } else {
-                                vec![]
-                            }
-                        }
-                        _ => {
-                            eprintln!("Invalid key");
-                            return;
-                        }
-                    }
+            // without a value the global section is printed, while a watch key is unset
+            let req = match key {
+                Some(key) if value.is_some() || scope != "global" => Request::SetKey {
+                    scope,
+                    key,
+                    value,
+                    reload: !no_reload,
+                },
+                key => Request::GetKey { scope, key },
+            };
+            match request(sock_name, req) {
+                Response::Value { value } => {
+                    println!("{}", value.as_deref().unwrap_or("(unset)").trim_end())
                 }
-                name => {
-                    let mut _watch = conf.watch.clone();
-                    _watch.retain(|f| f.name == name);
-                    let mut watch = _watch[0].clone();
-
-                    if key.is_none() & value.is_none() {
-                        println!("{:?}", watch);
-                        return;
-                    }
-
-                    if let Some(n) = value {
-                        let value = n.collect::<Vec<&String>>();
-                        match key.unwrap().as_str() {
-                            "poll_interval" => {
-                                if value.len() != 1 {
-                                    eprintln!("Invalid number of arguments");
-                                    return;
-                                }
-
-                                if let Ok(n) = value[0].parse::<i32>() {
-                                    if n.is_negative() {
-                                        eprintln!("Expected positive integer");
-                                        return;
-                                    }
-                                    watch.poll_interval = Some(n);
-                                } else {
-                                    eprintln!("Expected positive integer");
-                                    return;
-                                }
-                            }
-                            "write_delay" => {
-                                if value.len() != 1 {
-                                    eprintln!("Invalid number of arguments");
-                                    return;
-                                }
-
-                                if let Ok(n) = value[0].parse::<i32>() {
-                                    if n.is_negative() {
-                                        eprintln!("Expected positive integer");
-                                        return;
-                                    }
-                                    watch.write_delay = Some(n);
-                                } else {
-                                    eprintln!("Expected positive integer");
-                                    return;
-                                }
-                            }
-                            "ignore" => {
-                                watch.ignore =
-                                    value.iter().map(|d| d.to_string()).collect::<Vec<String>>();
-                            }
-                            _ => {
-                                eprintln!("Invalid key");
-                                return;
-                            }
-                        }
-                    } else {
-                        match key.unwrap().as_str() {
-                            "poll_interval" => {
-                                watch.poll_interval = None;
-                            }
-                            "write_delay" => {
-                                watch.write_delay = None;
-                            }
-                            "ignore" => {
-                                watch.ignore = vec![];
-                            }
-                            _ => {
-                                eprintln!("Invalid key");
-                                return;
-                            }
+                Response::Ok => {}
+                r => unexpected(r),
+            }
+        }
+        Some(("config", args)) => match args.subcommand() {
+            Some(("check", args)) => {
+                // a file given on the command line is checked here, cbakd might not be running
+                let (path, problems) = match args.get_one::<PathBuf>("PATH") {
+                    Some(p) => match fs::read_to_string(p) {
+                        Ok(text) => (
+                            p.display().to_string(),
+                            cbak_config::validate(&text, &fs::canonicalize(p).unwrap_or(p.clone())),
+                        ),
+                        Err(e) => {
+                            eprintln!("Could not read {}: {}", p.display(), e);
+                            std::process::exit(1);
                         }
+                    },
+                    None => match request(sock_name, Request::CheckConfig) {
+                        Response::ConfigCheck { path, problems } => (path, problems),
+                        r => unexpected(r),
+                    },
+                };
+                let errors = problems
+                    .iter()
+                    .filter(|p| p.severity == Severity::Error)
+                    .count();
+
+                if args.get_flag("json") {
+                    println!("{}", serde_json::to_string_pretty(&problems).unwrap());
+                } else {
+                    for p in &problems {
+                        println!("{}", p.in_file(&path));
                     }
-                    conf.watch
-                        .remove(conf.watch.iter().position(|f| f == &_watch[0]).unwrap());
-                    conf.watch.push(watch);
+                    println!(
+                        "{}: {} errors, {} warnings",
+                        path,
+                        errors,
+                        problems.len() - errors
+                    );
+                }
+                if errors > 0 {
+                    std::process::exit(1);
                 }
             }
-
-            let updated_conf = toml::to_string(&conf).unwrap();
-
-            fs::copy(&conf_file_path, format!("{}.bak", &conf_file_path)).unwrap();
-            fs::write(&conf_file_path, updated_conf).unwrap();
-            if !no_reload {
-                let conn =
-                    LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-                let mut conn = BufReader::new(conn);
-                conn.get_mut()
-                    .write_all(&[0b0000_0010, 0xA])
-                    .expect("write failure");
-            }
-        }
+            _ => unreachable!(),
+        },
         Some(("unwatch", args)) => {
             let name = args.get_one::<String>("NAME").unwrap().to_owned();
-
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            let mut buf = String::new();
-            conn.get_mut().write_all(&[0b0000_0100, 0xA]).unwrap();
-            conn.read_line(&mut buf).unwrap();
-            buf.pop();
-
-            let conf_file_path = buf;
-            let mut conf_file =
-                fs::File::open(&conf_file_path).expect("Failed to open config file");
-
-            let mut buf = String::new();
-            conf_file
-                .read_to_string(&mut buf)
-                .expect("Failed to open config file");
-            let mut conf = config::CbakConfig::new(&buf);
-            conf.watch.retain(|x| x.name != name);
-
-            let updated_conf = toml::to_string(&conf).unwrap();
-
-            fs::copy(&conf_file_path, format!("{}.bak", &conf_file_path)).unwrap();
-            fs::write(&conf_file_path, updated_conf).unwrap();
-
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            conn.get_mut()
-                .write_all(&[0b0000_0010, 0xA])
-                .expect("write failure");
+            request(sock_name, Request::RemoveWatch { name });
         }
         Some(("list", _)) => {
-            let conn = LocalSocketStream::connect(sock_name).expect("failed to connect to socket");
-            let mut conn = BufReader::new(conn);
-            let mut buf = String::new();
-            conn.get_mut().write_all(&[0b0000_0100, 0xA]).unwrap();
-            conn.read_line(&mut buf).unwrap();
-            buf.pop();
-
-            let conf_file_path = buf;
-            let mut conf_file =
-                fs::File::open(&conf_file_path).expect("Failed to open config file");
-
-            let mut buf = String::new();
-            conf_file
-                .read_to_string(&mut buf)
-                .expect("Failed to open config file");
-            let conf = config::CbakConfig::new(&buf);
-            let table = conf
-                .watch
+            let watches = match request(sock_name, Request::ListWatches) {
+                Response::Watches { watches } => watches,
+                r => unexpected(r),
+            };
+
+            let errors = match request(sock_name, Request::Errors) {
+                Response::Errors { watches } => watches
+                    .into_iter()
+                    .filter_map(|w| {
+                        Some((
+                            w.name,
+                            format!("[{}] {}", w.time.unwrap_or_default(), w.error?),
+                        ))
+                    })
+                    .collect::<HashMap<String, String>>(),
+                r => unexpected(r),
+            };
+
+            let table = watches
                 .iter()
                 .map(|i| {
                     vec![
                         i.name.clone().cell(),
                         i.directory.clone().cell().justify(Justify::Right),
+                        errors.get(&i.name).cloned().unwrap_or_default().cell(),
                     ]
                 })
                 .collect::<Vec<Vec<CellStruct>>>()
@@ -394,6 +480,300 @@ fn main() {
                 .title(vec![
                     "Name".cell().bold(true),
                     "Directory".cell().bold(true),
+                    "Last error".cell().bold(true),
+                ])
+                .bold(true);
+            print_stdout(table).unwrap();
+        }
+        Some(("log", args)) => {
+            let commits = match request(
+                sock_name,
+                Request::Log {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    path: args.get_one::<String>("PATH").cloned(),
+                    since: args.get_one::<String>("since").cloned(),
+                    until: args.get_one::<String>("until").cloned(),
+                    limit: args.get_one::<usize>("max-count").copied(),
+                },
+            ) {
+                Response::Log { commits } => commits,
+                r => unexpected(r),
+            };
+
+            if args.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&commits).unwrap());
+                return;
+            }
+
+            let table = commits
+                .iter()
+                .map(|c| {
+                    let time = local_time(&c.time);
+                    let files = c
+                        .files
+                        .iter()
+                        .map(|f| match (&f.change, &f.old_path) {
+                            (ChangeKind::Renamed, Some(old)) => format!("R {} -> {}", old, f.path),
+                            (ChangeKind::Added, _) => format!("A {}", f.path),
+                            (ChangeKind::Deleted, _) => format!("D {}", f.path),
+                            _ => format!("M {}", f.path),
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    vec![
+                        c.id[..8].to_string().cell(),
+                        time.cell(),
+                        files.cell(),
+                        c.message.lines().next().unwrap_or("").to_string().cell(),
+                    ]
+                })
+                .collect::<Vec<Vec<CellStruct>>>()
+                .table()
+                .title(vec![
+                    "Commit".cell().bold(true),
+                    "Time".cell().bold(true),
+                    "Files".cell().bold(true),
+                    "Message".cell().bold(true),
+                ])
+                .bold(true);
+            print_stdout(table).unwrap();
+        }
+        Some(("diff", args)) => {
+            let mut revs = args
+                .get_many::<String>("REV")
+                .into_iter()
+                .flatten()
+                .cloned();
+            let patch = match request(
+                sock_name,
+                Request::Diff {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    from: revs.next(),
+                    to: revs.next(),
+                    path: args.get_one::<String>("PATH").cloned(),
+                },
+            ) {
+                Response::Diff { patch } => patch,
+                r => unexpected(r),
+            };
+
+            if std::io::stdout().is_terminal() {
+                patch.lines().for_each(|l| println!("{}", colorize(l)));
+            } else {
+                print!("{}", patch);
+            }
+        }
+        Some(("restore", args)) => {
+            let dry_run = args.get_flag("dry-run");
+            let (from, files, commit) = match request(
+                sock_name,
+                Request::Restore {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    rev: args.get_one::<String>("REV").unwrap().to_owned(),
+                    paths: args
+                        .get_many::<String>("PATH")
+                        .into_iter()
+                        .flatten()
+                        .cloned()
+                        .collect(),
+                    dry_run,
+                },
+            ) {
+                Response::Restored {
+                    from,
+                    files,
+                    commit,
+                } => (from, files, commit),
+                r => unexpected(r),
+            };
+
+            if files.is_empty() {
+                println!("Nothing to restore, the files already match {}", &from[..8]);
+                return;
+            }
+            println!(
+                "{} {} files from {}:",
+                if dry_run { "Would restore" } else { "Restored" },
+                files.len(),
+                &from[..8]
+            );
+            for f in &files {
+                let change = match f.change {
+                    ChangeKind::Added => "A",
+                    ChangeKind::Deleted => "D",
+                    _ => "M",
+                };
+                println!("{} {}", change, f.path);
+            }
+            if let Some(id) = commit {
+                println!("Committed as {}", &id[..8]);
+            }
+        }
+        Some(("show", args)) => {
+            let mut client = connect(sock_name);
+            let res = client.request(&Request::Show {
+                name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                rev: args.get_one::<String>("REV").unwrap().to_owned(),
+                path: args.get_one::<String>("PATH").unwrap().to_owned(),
+            });
+            let size = match res {
+                Ok(Response::FileContent { size }) => size,
+                Ok(r) => unexpected(r),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = client.read_content(size, &mut std::io::stdout().lock()) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(("status", args)) => {
+            let mut watches = match request(sock_name, Request::Status) {
+                Response::Status { watches } => watches,
+                r => unexpected(r),
+            };
+            if let Some(name) = args.get_one::<String>("NAME") {
+                watches.retain(|w| &w.name == name);
+                if watches.is_empty() {
+                    eprintln!("No watch named {}", name);
+                    std::process::exit(1);
+                }
+            }
+
+            if args.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&watches).unwrap());
+                return;
+            }
+
+            let table = watches
+                .iter()
+                .map(|w| {
+                    vec![
+                        w.name.clone().cell(),
+                        match (&w.paused_until, &w.next_restart) {
+                            (Some(t), _) => format!("{} until {}", w.state, local_time(t)),
+                            (_, Some(t)) => format!(
+                                "{}, restart {} at {}",
+                                w.state,
+                                w.restarts + 1,
+                                local_time(t)
+                            ),
+                            _ => w.state.to_string(),
+                        }
+                        .cell(),
+                        w.last_change
+                            .as_deref()
+                            .map(local_time)
+                            .unwrap_or_default()
+                            .cell(),
+                        w.last_commit
+                            .as_ref()
+                            .map(|c| format!("{} {}", &c.id[..8], local_time(&c.time)))
+                            .unwrap_or_default()
+                            .cell(),
+                        w.pending.cell().justify(Justify::Right),
+                        match (&w.last_error_time, &w.last_error) {
+                            (Some(t), Some(e)) => format!("[{}] {}", local_time(t), e),
+                            _ => String::new(),
+                        }
+                        .cell(),
+                    ]
+                })
+                .collect::<Vec<Vec<CellStruct>>>()
+                .table()
+                .title(vec![
+                    "Name".cell().bold(true),
+                    "State".cell().bold(true),
+                    "Last change".cell().bold(true),
+                    "Last commit".cell().bold(true),
+                    "Pending".cell().bold(true),
+                    "Last error".cell().bold(true),
+                ])
+                .bold(true);
+            print_stdout(table).unwrap();
+        }
+        Some(("pause", args)) => {
+            request(
+                sock_name,
+                Request::Pause {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    seconds: args.get_one::<u64>("for").copied(),
+                },
+            );
+        }
+        Some(("resume", args)) => {
+            request(
+                sock_name,
+                Request::Resume {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    commit: !args.get_flag("no-commit"),
+                },
+            );
+        }
+        Some(("commit", args)) => {
+            match request(
+                sock_name,
+                Request::Commit {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    message: args.get_one::<String>("message").unwrap().to_owned(),
+                    author: args.get_one::<String>("author").cloned(),
+                },
+            ) {
+                // only the id on stdout, for scripts
+                Response::Committed { commit: Some(id) } => println!("{}", id),
+                Response::Committed { commit: None } => eprintln!("Nothing to commit"),
+                r => unexpected(r),
+            }
+        }
+        Some(("tag", args)) => {
+            let tag = args.get_one::<String>("TAG").unwrap().to_owned();
+            match request(
+                sock_name,
+                Request::Tag {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                    tag: tag.clone(),
+                    message: args.get_one::<String>("message").cloned(),
+                },
+            ) {
+                Response::Tagged { commit } => println!("Tagged {} as {}", &commit[..8], tag),
+                r => unexpected(r),
+            }
+        }
+        Some(("tags", args)) => {
+            let tags = match request(
+                sock_name,
+                Request::Tags {
+                    name: args.get_one::<String>("NAME").unwrap().to_owned(),
+                },
+            ) {
+                Response::Tags { tags } => tags,
+                r => unexpected(r),
+            };
+
+            if args.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&tags).unwrap());
+                return;
+            }
+
+            let table = tags
+                .iter()
+                .map(|t| {
+                    vec![
+                        t.name.clone().cell(),
+                        t.commit[..8].to_string().cell(),
+                        local_time(&t.time).cell(),
+                        t.message.lines().next().unwrap_or("").to_string().cell(),
+                    ]
+                })
+                .collect::<Vec<Vec<CellStruct>>>()
+                .table()
+                .title(vec![
+                    "Tag".cell().bold(true),
+                    "Commit".cell().bold(true),
+                    "Time".cell().bold(true),
+                    "Message".cell().bold(true),
                 ])
                 .bold(true);
             print_stdout(table).unwrap();
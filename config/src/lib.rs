@@ -0,0 +1,333 @@
+//! The configuration format of cbakd, shared with cbak so both sides agree on the schema, the defaults & what
+//! counts as a valid config.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{fs, path::Path};
+
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+
+mod validate;
+pub use validate::{validate, ConfigProblem, InvalidConfig, Severity};
+
+// Any struct prefixed with an _ is what the configuration is seralized into,
+// the "normal" structs are what the daemon runs with, the _ structs are converted into the "normal" ones
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct _CbakConfig {
+    pub global: _GlobalConfig,
+    pub watch: Option<Vec<_DirConfig>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CbakConfig {
+    pub watch: Vec<DirConfig>,
+    pub config_file_path: PathBuf,
+    /// Problems found in the file that don't stop cbakd from running with it
+    pub warnings: Vec<ConfigProblem>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct _GlobalConfig {
+    pub ignore: Vec<String>,
+    pub poll_interval: i32,
+    pub write_delay: i32,
+    #[serde(default)]
+    pub watcher: WatcherKind,
+    #[serde(default)]
+    pub vcs: VcsKind,
+    pub commit_message: Option<String>,
+    pub data_dir: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct _DirConfig {
+    pub name: Option<String>,
+    pub directory: String,
+    pub ignore: Vec<String>,
+    pub poll_interval: Option<i32>,
+    pub write_delay: Option<i32>,
+    pub watcher: Option<WatcherKind>,
+    pub vcs: Option<VcsKind>,
+    pub commit_message: Option<String>,
+    pub git_dir: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirConfig {
+    pub name: String,
+    pub directory: String,
+    pub ignore: Vec<Regex>,
+    pub poll_interval: i32,
+    pub write_delay: i32,
+    pub watcher: WatcherKind,
+    pub vcs: VcsKind,
+    pub commit_message: String,
+    /// Where the repository lives, the watched directory is used as its work tree
+    pub git_dir: PathBuf,
+}
+
+// Regex has no PartialEq, patterns are compared by their source
+impl PartialEq for DirConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.directory == other.directory
+            && self.ignore.len() == other.ignore.len()
+            && self
+                .ignore
+                .iter()
+                .zip(&other.ignore)
+                .all(|(a, b)| a.as_str() == b.as_str())
+            && self.poll_interval == other.poll_interval
+            && self.write_delay == other.write_delay
+            && self.watcher == other.watcher
+            && self.vcs == other.vcs
+            && self.commit_message == other.commit_message
+            && self.git_dir == other.git_dir
+    }
+}
+
+/// Keys `cbak edit-config global` accepts
+pub const GLOBAL_KEYS: &[&str] = &[
+    "ignore",
+    "poll_interval",
+    "write_delay",
+    "watcher",
+    "vcs",
+    "commit_message",
+    "data_dir",
+];
+
+/// Keys `cbak edit-config <NAME>` accepts
+pub const WATCH_KEYS: &[&str] = &[
+    "directory",
+    "ignore",
+    "poll_interval",
+    "write_delay",
+    "watcher",
+    "vcs",
+    "commit_message",
+    "git_dir",
+];
+
+/// Written to the config file if there is none yet
+pub const DEFAULT_CONFIG: &str = "[global]
+ignore = [\".git\\\\\\\\\", \"\\\\\\\\.git\", \"/.git\", \".git/\"]
+poll_interval = 30
+write_delay = 30
+";
+
+/// Commit message template used when neither the watch nor the global section sets one, see `message::render`
+pub const DEFAULT_COMMIT_MESSAGE: &str = "[{hostname}] {name}: {summary}\n\n{files}";
+
+/// Environment variable naming the config file when `--config` isn't given
+pub const CONFIG_ENV: &str = "CBAKD_CONFIG";
+
+/// Config file of the system wide daemon
+pub const SYSTEM_CONFIG: &str = "/etc/cbakd/config.toml";
+
+/// Directory the repositories of watches without an explicit `git_dir` are kept in, see `default_data_dir`
+pub const DEFAULT_DATA_DIR: &str = "/var/lib/cbakd";
+
+/// How a worker finds out that something in its directory changed.
+/// `poll` is kept as a fallback for filesystems without inotify support (NFS, FUSE, ...)
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatcherKind {
+    #[default]
+    Inotify,
+    Poll,
+}
+
+/// Which git implementation a worker commits with.
+/// `git` shells out to the git binary, which then has to be installed & on the PATH.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VcsKind {
+    #[default]
+    Libgit2,
+    Git,
+}
+
+impl WatcherKind {
+    pub const NAMES: [&'static str; 2] = ["inotify", "poll"];
+}
+
+impl FromStr for WatcherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inotify" => Ok(WatcherKind::Inotify),
+            "poll" => Ok(WatcherKind::Poll),
+            _ => Err(format!("expected one of: {}", Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl VcsKind {
+    pub const NAMES: [&'static str; 2] = ["libgit2", "git"];
+}
+
+impl FromStr for VcsKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "libgit2" => Ok(VcsKind::Libgit2),
+            "git" => Ok(VcsKind::Git),
+            _ => Err(format!("expected one of: {}", Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl CbakConfig {
+    /// Picks the config file: `explicit` (from `--config`), then `$CBAKD_CONFIG`, then the first one that exists of
+    /// /etc/cbakd/config.toml & `$XDG_CONFIG_HOME/cbakd/config.toml`.
+    /// If there is none yet, a new one goes into /etc/cbakd if that directory exists, otherwise into the XDG directory.
+    /// Nothing is created, that's up to `load`
+    pub fn locate(explicit: Option<PathBuf>) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if let Some(p) = explicit.or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from)) {
+            return Ok(p);
+        }
+        let system = PathBuf::from(SYSTEM_CONFIG);
+        let user = xdg_dir("XDG_CONFIG_HOME", ".config").map(|d| d.join("config.toml"));
+        if system.exists() {
+            return Ok(system);
+        }
+        match user {
+            Some(u) if u.exists() => Ok(u),
+            _ if system.parent().is_some_and(Path::is_dir) => Ok(system),
+            // `load` creates the directory
+            Some(u) => Ok(u),
+            None => {
+                Err(format!("no config file found, pass --config or set {}", CONFIG_ENV).into())
+            }
+        }
+    }
+
+    /// Loads a config file, writing the default config to it first if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            // only the XDG directory is made for a new config, any other missing directory is more likely a typo
+            let dir = path
+                .parent()
+                .filter(|d| xdg_dir("XDG_CONFIG_HOME", ".config").as_deref() == Some(*d));
+            if let Some(dir) = dir {
+                fs::create_dir_all(dir)
+                    .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+            }
+            fs::write(path, DEFAULT_CONFIG)
+                .map_err(|e| format!("could not create {}: {}", path.display(), e))?;
+        }
+        Self::read(path)
+    }
+
+    /// Loads a config file that has to exist already, e.g. to check it without leaving anything behind
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        Self::parse(&text, fs::canonicalize(path)?)
+    }
+
+    /// Builds a config from the contents of a config file, failing with an `InvalidConfig` listing every problem
+    /// if there is anything the workers couldn't run with
+    pub fn parse(
+        text: &str,
+        config_file_path: PathBuf,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (errors, warnings): (Vec<ConfigProblem>, Vec<ConfigProblem>) =
+            validate(text, &config_file_path)
+                .into_iter()
+                .partition(|p| p.severity == Severity::Error);
+        if !errors.is_empty() {
+            return Err(Box::new(InvalidConfig { problems: errors }));
+        }
+        let config: _CbakConfig = toml::from_str(text)?;
+        let global_ignore = compile(&config.global.ignore)?;
+        let default_data_dir = default_data_dir(&config_file_path);
+
+        let mut watch = vec![];
+        for i in config.watch.iter().flatten() {
+            let mut ignore = compile(&i.ignore)?;
+            ignore.extend(global_ignore.iter().cloned());
+            let name = i.name.clone().unwrap_or_else(|| i.directory.clone());
+            watch.push(DirConfig {
+                name: name.clone(),
+                directory: i.directory.clone(),
+                ignore,
+                poll_interval: i.poll_interval.unwrap_or(config.global.poll_interval),
+                write_delay: i.write_delay.unwrap_or(config.global.write_delay),
+                watcher: i.watcher.unwrap_or(config.global.watcher),
+                vcs: i.vcs.unwrap_or(config.global.vcs),
+                commit_message: i
+                    .commit_message
+                    .clone()
+                    .or_else(|| config.global.commit_message.clone())
+                    .unwrap_or_else(|| DEFAULT_COMMIT_MESSAGE.to_string()),
+                git_dir: git_dir(&config.global, i, &name, &default_data_dir),
+            });
+        }
+
+        Ok(Self {
+            config_file_path,
+            watch,
+            warnings,
+        })
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, Box<dyn std::error::Error>> {
+    patterns
+        .iter()
+        .map(|f| Regex::new(f).map_err(|e| format!("invalid ignore pattern {:?}: {}", f, e).into()))
+        .collect()
+}
+
+/// `$<var>/cbakd`, or `~/<fallback>/cbakd` if the variable isn't set
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    // the XDG spec says to ignore relative paths, which includes an empty variable
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(fallback)))
+        .map(|d| d.join("cbakd"))
+}
+
+/// Where repositories go when `data_dir` isn't set: `$XDG_DATA_HOME/cbakd` for a config in the XDG config directory,
+/// which a daemon running as a normal user can write, otherwise `DEFAULT_DATA_DIR`
+pub fn default_data_dir(config_file: &Path) -> PathBuf {
+    let user_config = xdg_dir("XDG_CONFIG_HOME", ".config");
+    let is_user_config = user_config.is_some_and(|d| {
+        // config_file is canonical, the XDG directory may be reached through a symlink
+        config_file.starts_with(&d)
+            || fs::canonicalize(&d).is_ok_and(|d| config_file.starts_with(d))
+    });
+    match xdg_dir("XDG_DATA_HOME", ".local/share") {
+        Some(d) if is_user_config => d,
+        _ => PathBuf::from(DEFAULT_DATA_DIR),
+    }
+}
+
+/// Resolves where the repository of a watch is kept, `default_data_dir` unless `data_dir` is set.
+/// Watches set up before `git_dir` existed keep using the `.git` inside the watched directory.
+pub(crate) fn git_dir(
+    global: &_GlobalConfig,
+    watch: &_DirConfig,
+    name: &str,
+    default_data_dir: &Path,
+) -> PathBuf {
+    if let Some(d) = &watch.git_dir {
+        return PathBuf::from(d);
+    }
+    let in_tree = Path::new(&watch.directory).join(".git");
+    if in_tree.exists() {
+        return in_tree;
+    }
+    let data_dir = global.data_dir.as_deref().map(Path::new);
+    data_dir.unwrap_or(default_data_dir).join(format!(
+        "{}.git",
+        name.trim_start_matches('/').replace('/', "_")
+    ))
+}
@@ -0,0 +1,580 @@
+//! Checks a config file for everything cbakd can't run with, reporting each problem with where it is in the file.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+use toml_edit::{ImDocument, Item, TableLike};
+
+use crate::{_CbakConfig, VcsKind, WatcherKind, GLOBAL_KEYS, WATCH_KEYS};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// cbakd won't load the config
+    Error,
+    /// cbakd runs, but probably not the way it was meant to
+    Warning,
+}
+
+/// One problem found in a config file
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// 1-based, `None` if the problem isn't at one place in the file
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// `global` or `watch <name>`
+    pub scope: Option<String>,
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        match self.severity {
+            Severity::Error => f.write_str("error: ")?,
+            Severity::Warning => f.write_str("warning: ")?,
+        }
+        if let Some(scope) = &self.scope {
+            write!(f, "{}: ", scope)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl ConfigProblem {
+    /// Prefixed with the file the problem is in, like compilers print errors
+    pub fn in_file(&self, path: &str) -> String {
+        match self.line {
+            Some(_) => format!("{}:{}", path, self),
+            None => format!("{}: {}", path, self),
+        }
+    }
+}
+
+/// Returned by `CbakConfig::parse` when a config has errors, with every problem found in it
+#[derive(Debug)]
+pub struct InvalidConfig {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self
+            .problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>();
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+/// Checks the text of a config file, returning every problem found in it.
+/// `config_file` is where the text is from, it decides where repositories go by default
+pub fn validate(text: &str, config_file: &Path) -> Vec<ConfigProblem> {
+    let mut checker = Checker {
+        text,
+        problems: vec![],
+    };
+    let doc = match ImDocument::parse(text) {
+        Ok(d) => d,
+        Err(e) => {
+            let message = e
+                .message()
+                .trim_end()
+                .lines()
+                .collect::<Vec<&str>>()
+                .join(", ");
+            checker.error(e.span(), None, None, message);
+            return checker.problems;
+        }
+    };
+
+    for (key, item) in doc.iter() {
+        match key {
+            "global" => {}
+            "watch" => {}
+            _ => checker.warning(
+                doc.key(key).and_then(|k| k.span()).or_else(|| item.span()),
+                None,
+                Some(key),
+                "unknown section, it is ignored",
+            ),
+        }
+    }
+
+    match doc.get("global") {
+        Some(Item::Table(t)) => checker.section(t, t.span(), "global", GLOBAL_KEYS, true),
+        Some(i) => checker.error(i.span(), Some("global"), None, "has to be a table"),
+        None => checker.error(
+            None,
+            Some("global"),
+            None,
+            "the [global] section is missing",
+        ),
+    }
+
+    let mut watches = vec![];
+    match doc.get("watch") {
+        None => {}
+        Some(Item::ArrayOfTables(a)) => {
+            watches.extend(a.iter().map(|t| (t as &dyn TableLike, t.span())))
+        }
+        Some(Item::Value(toml_edit::Value::Array(a))) => {
+            for v in a.iter() {
+                match v.as_inline_table() {
+                    Some(t) => watches.push((t as &dyn TableLike, v.span())),
+                    None => checker.error(v.span(), None, Some("watch"), "has to be a table"),
+                }
+            }
+        }
+        Some(i) => checker.error(
+            i.span(),
+            None,
+            Some("watch"),
+            "has to be an array of tables, written as [[watch]]",
+        ),
+    }
+
+    // by name, with where each watch starts
+    let mut names: BTreeMap<String, Option<Range<usize>>> = BTreeMap::new();
+    let mut directories: Vec<(String, PathBuf)> = vec![];
+    // scope & where it starts, of every watch in order
+    let mut scopes = vec![];
+    for (i, (table, span)) in watches.into_iter().enumerate() {
+        let name = table
+            .get("name")
+            .or_else(|| table.get("directory"))
+            .and_then(Item::as_str)
+            .map(str::to_string);
+        let scope = match &name {
+            Some(n) => format!("watch {}", n),
+            None => format!("watch #{}", i + 1),
+        };
+        checker.section(table, span.clone(), &scope, WATCH_KEYS, false);
+        scopes.push((scope.clone(), span.clone()));
+
+        if let Some(n) = name {
+            if let Some(first) = names.get(&n) {
+                let first = first.clone().map(|s| checker.position(s.start).0);
+                checker.error(
+                    span.clone(),
+                    Some(&scope),
+                    None,
+                    match first {
+                        Some(line) => {
+                            format!("a watch with this name is already defined on line {}", line)
+                        }
+                        None => "a watch with this name is already defined".to_string(),
+                    },
+                );
+            } else {
+                names.insert(n.clone(), span.clone());
+            }
+            if let Some(dir) = table.get("directory").and_then(Item::as_str) {
+                // symlinks & .. shouldn't hide an overlap
+                let path = fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir));
+                for (other, other_path) in &directories {
+                    if path.starts_with(other_path) || other_path.starts_with(&path) {
+                        checker.warning(
+                            table.get("directory").and_then(Item::span),
+                            Some(&scope),
+                            Some("directory"),
+                            format!(
+                                "overlaps with watch {}, files in both are committed twice unless ignored in one",
+                                other
+                            ),
+                        );
+                    }
+                }
+                directories.push((n, path));
+            }
+        }
+    }
+
+    // catches anything the checks above don't know about
+    if !checker.has_errors() {
+        match toml::from_str::<_CbakConfig>(text) {
+            Ok(config) => {
+                checker.shared_git_dirs(&config, &scopes, &crate::default_data_dir(config_file))
+            }
+            Err(e) => {
+                let (line, column) = match e.line_col() {
+                    Some((l, c)) => (Some(l + 1), Some(c + 1)),
+                    None => (None, None),
+                };
+                checker.problems.push(ConfigProblem {
+                    severity: Severity::Error,
+                    line,
+                    column,
+                    scope: None,
+                    key: None,
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+    // problems without a place in the file come first
+    checker.problems.sort_by_key(|p| p.line);
+    checker.problems
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Checker<'_> {
+    fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| p.severity == Severity::Error)
+    }
+
+    /// 1-based line & column of a byte offset into the text
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rfind('\n')
+            .map_or(before, |i| &before[i + 1..])
+            .chars()
+            .count()
+            + 1;
+        (line, column)
+    }
+
+    fn push(
+        &mut self,
+        severity: Severity,
+        span: Option<Range<usize>>,
+        scope: Option<&str>,
+        key: Option<&str>,
+        message: impl Into<String>,
+    ) {
+        let (line, column) = match span.map(|s| self.position(s.start)) {
+            Some((l, c)) => (Some(l), Some(c)),
+            None => (None, None),
+        };
+        self.problems.push(ConfigProblem {
+            severity,
+            line,
+            column,
+            scope: scope.map(str::to_string),
+            key: key.map(str::to_string),
+            message: message.into(),
+        });
+    }
+
+    fn error(
+        &mut self,
+        span: Option<Range<usize>>,
+        scope: Option<&str>,
+        key: Option<&str>,
+        message: impl Into<String>,
+    ) {
+        self.push(Severity::Error, span, scope, key, message)
+    }
+
+    fn warning(
+        &mut self,
+        span: Option<Range<usize>>,
+        scope: Option<&str>,
+        key: Option<&str>,
+        message: impl Into<String>,
+    ) {
+        self.push(Severity::Warning, span, scope, key, message)
+    }
+
+    /// Checks the keys of `[global]` or of one `[[watch]]`
+    fn section(
+        &mut self,
+        table: &dyn TableLike,
+        span: Option<Range<usize>>,
+        scope: &str,
+        keys: &[&str],
+        global: bool,
+    ) {
+        let required: &[&str] = if global {
+            &["ignore", "poll_interval", "write_delay"]
+        } else {
+            &["directory", "ignore"]
+        };
+        for key in required {
+            if !table.contains_key(key) {
+                self.error(span.clone(), Some(scope), Some(key), "is required");
+            }
+        }
+
+        for (key, item) in table.iter() {
+            let at = item.span();
+            let scope = Some(scope);
+            match key {
+                "ignore" => match item.as_array() {
+                    Some(patterns) => {
+                        for p in patterns.iter() {
+                            match p.as_str() {
+                                Some(s) => {
+                                    if let Err(e) = Regex::new(s) {
+                                        self.error(
+                                            p.span(),
+                                            scope,
+                                            Some(key),
+                                            format!("invalid pattern {:?}: {}", s, e),
+                                        );
+                                    }
+                                }
+                                None => self.error(
+                                    p.span(),
+                                    scope,
+                                    Some(key),
+                                    "patterns have to be strings",
+                                ),
+                            }
+                        }
+                    }
+                    None => self.error(at, scope, Some(key), "has to be a list of patterns"),
+                },
+                "poll_interval" | "write_delay" => match item.as_integer() {
+                    Some(n) if n <= 0 => {
+                        self.error(at, scope, Some(key), "has to be greater than 0")
+                    }
+                    Some(n) if n > i32::MAX as i64 => {
+                        self.error(at, scope, Some(key), "is too large")
+                    }
+                    Some(_) => {}
+                    None => self.error(at, scope, Some(key), "has to be a number of seconds"),
+                },
+                "watcher" => self.parsed::<WatcherKind>(item, scope, key),
+                "vcs" => self.parsed::<VcsKind>(item, scope, key),
+                "directory" => match item.as_str() {
+                    Some(d) if !Path::new(d).is_absolute() => {
+                        self.error(at, scope, Some(key), "has to be an absolute path")
+                    }
+                    Some(d) if !Path::new(d).is_dir() => {
+                        self.warning(at, scope, Some(key), format!("{} is not a directory", d))
+                    }
+                    Some(d) => {
+                        if let Err(e) = fs::read_dir(d) {
+                            self.warning(
+                                at,
+                                scope,
+                                Some(key),
+                                format!("{} can't be read: {}", d, e),
+                            );
+                        }
+                    }
+                    None => self.error(at, scope, Some(key), "has to be a string"),
+                },
+                "name" if !global => {
+                    if item.as_str().is_none() {
+                        self.error(at, scope, Some(key), "has to be a string");
+                    }
+                }
+                _ if keys.contains(&key) => {
+                    if item.as_str().is_none() {
+                        self.error(at, scope, Some(key), "has to be a string");
+                    }
+                }
+                _ => self.warning(
+                    table.key(key).and_then(|k| k.span()).or(at),
+                    scope,
+                    Some(key),
+                    "unknown key, it is ignored",
+                ),
+            }
+        }
+    }
+
+    /// Two watches with the same repository would share its index & HEAD, which breaks both.
+    /// Default repositories are named after the watch, so e.g. `/etc/a_b` & `/etc/a/b` end up in the same one
+    fn shared_git_dirs(
+        &mut self,
+        config: &_CbakConfig,
+        scopes: &[(String, Option<Range<usize>>)],
+        default_data_dir: &Path,
+    ) {
+        let mut seen: BTreeMap<PathBuf, &str> = BTreeMap::new();
+        for (w, (scope, span)) in config.watch.iter().flatten().zip(scopes) {
+            let name = w.name.as_deref().unwrap_or(&w.directory);
+            let dir = crate::git_dir(&config.global, w, name, default_data_dir);
+            match seen.get(&dir) {
+                Some(other) => self.error(
+                    span.clone(),
+                    Some(scope),
+                    Some("git_dir"),
+                    format!(
+                        "{} is the repository of watch {} already, give one of them its own git_dir",
+                        dir.display(),
+                        other
+                    ),
+                ),
+                None => {
+                    seen.insert(dir, name);
+                }
+            }
+        }
+    }
+
+    /// Checks a string key that has to be one of a fixed set of values
+    fn parsed<T: FromStr<Err = String>>(&mut self, item: &Item, scope: Option<&str>, key: &str) {
+        match item.as_str().map(T::from_str) {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => self.error(item.span(), scope, Some(key), e),
+            None => self.error(item.span(), scope, Some(key), "has to be a string"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLOBAL: &str = "[global]\nignore = []\npoll_interval = 30\nwrite_delay = 30\n";
+
+    fn check(text: &str) -> Vec<String> {
+        validate(text, Path::new(crate::SYSTEM_CONFIG))
+            .iter()
+            .map(ConfigProblem::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let text = format!(
+            "{}\n[[watch]]\nname = \"root\"\ndirectory = \"/\"\nignore = [\"/proc\"]\nwatcher = \"poll\"\nvcs = \"git\"\n",
+            GLOBAL
+        );
+        assert_eq!(check(&text), Vec::<String>::new());
+    }
+
+    #[test]
+    fn syntax_error() {
+        let problems = validate("[global\nignore = []\n", Path::new(crate::SYSTEM_CONFIG));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Error);
+        assert_eq!(problems[0].line, Some(1));
+    }
+
+    #[test]
+    fn missing_keys() {
+        assert_eq!(
+            check("[global]\nignore = []\n\n[[watch]]\ndirectory = \"/\"\n"),
+            [
+                "1:1: error: global: poll_interval: is required",
+                "1:1: error: global: write_delay: is required",
+                "4:1: error: watch /: ignore: is required",
+            ]
+        );
+        assert_eq!(
+            check("[[watch]]\ndirectory = \"/\"\nignore = []\n"),
+            ["error: global: the [global] section is missing"]
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        let text = format!(
+            "{}\n[[watch]]\ndirectory = \"etc\"\nignore = [\"(\", 1]\npoll_interval = 0\nwatcher = \"fanotify\"\n",
+            GLOBAL
+        );
+        let problems = validate(&text, Path::new(crate::SYSTEM_CONFIG));
+        let at = |key: &str| {
+            problems
+                .iter()
+                .find(|p| p.key.as_deref() == Some(key))
+                .map(|p| (p.severity, p.line))
+        };
+        assert_eq!(at("directory"), Some((Severity::Error, Some(7))));
+        assert_eq!(at("poll_interval"), Some((Severity::Error, Some(9))));
+        assert_eq!(at("watcher"), Some((Severity::Error, Some(10))));
+        let ignore = problems
+            .iter()
+            .filter(|p| p.key.as_deref() == Some("ignore"))
+            .count();
+        assert_eq!(ignore, 2);
+    }
+
+    #[test]
+    fn unknown_keys_are_warnings() {
+        let text = format!("{}colour = \"blue\"\n\n[extra]\n", GLOBAL);
+        assert_eq!(
+            check(&text),
+            [
+                "5:1: warning: global: colour: unknown key, it is ignored",
+                "7:2: warning: extra: unknown section, it is ignored",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let text = format!(
+            "{}\n[[watch]]\nname = \"etc\"\ndirectory = \"/\"\nignore = []\n\n[[watch]]\nname = \"etc\"\ndirectory = \"/\"\nignore = []\n",
+            GLOBAL
+        );
+        let problems = check(&text);
+        assert!(problems
+            .iter()
+            .any(|p| p
+                == "11:1: error: watch etc: a watch with this name is already defined on line 6"));
+    }
+
+    #[test]
+    fn overlapping_directories() {
+        let tmp = std::env::temp_dir();
+        let text = format!(
+            "{}\n[[watch]]\nname = \"all\"\ndirectory = \"/\"\nignore = []\n\n[[watch]]\nname = \"tmp\"\ndirectory = {:?}\nignore = []\n",
+            GLOBAL, tmp
+        );
+        assert_eq!(
+            check(&text),
+            ["13:13: warning: watch tmp: directory: overlaps with watch all, files in both are committed twice unless ignored in one"]
+        );
+    }
+
+    #[test]
+    fn shared_git_dir() {
+        let text = format!(
+            "{}data_dir = \"/srv/cbakd\"\n\n[[watch]]\nname = \"a/b\"\ndirectory = \"/\"\nignore = []\n\n[[watch]]\nname = \"a_b\"\ndirectory = \"/\"\nignore = []\n",
+            GLOBAL
+        );
+        assert!(check(&text).contains(
+            &"12:1: error: watch a_b: git_dir: /srv/cbakd/a_b.git is the repository of watch a/b already, give one of them its own git_dir"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn inline_watch_tables() {
+        let text = format!(
+            "watch = [{{ name = \"root\", directory = \"/\", ignore = [] }}, 1]\n{}",
+            GLOBAL
+        );
+        assert_eq!(check(&text), ["1:59: error: watch: has to be a table"]);
+    }
+
+    #[test]
+    fn in_file() {
+        let problems = validate("[global]\n", Path::new(crate::SYSTEM_CONFIG));
+        assert_eq!(
+            problems[0].in_file("/etc/cbakd/config.toml"),
+            "/etc/cbakd/config.toml:1:1: error: global: ignore: is required"
+        );
+    }
+}
@@ -0,0 +1,534 @@
+//! Changes to the configuration file requested over the socket.
+//! Edits work on the raw TOML document so only the touched keys change, comments, key order, formatting & keys cbakd
+//! doesn't know about survive. The result has to load as a config before it replaces the file.
+
+use std::{
+    fmt::Display,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use cbak_config::{_DirConfig, CbakConfig, GLOBAL_KEYS, WATCH_KEYS};
+use cbak_proto::ErrorKind;
+use log::warn;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, TableLike, Value};
+
+use crate::restore::write_file;
+
+/// Backups of the config file kept next to it, older ones are deleted
+const BACKUPS: usize = 10;
+
+/// Timestamp in the name of a backup, sorts the same as the times it stands for
+const BACKUP_TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+#[derive(Debug)]
+pub enum EditError {
+    /// No watch with that name
+    NotFound(String),
+    /// The edit would leave a config behind that cbakd can't run with
+    Invalid(String),
+    Io(io::Error),
+}
+
+impl EditError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            EditError::NotFound(_) => ErrorKind::NotFound,
+            EditError::Invalid(_) => ErrorKind::InvalidConfig,
+            EditError::Io(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::NotFound(name) => write!(f, "no watch named {}", name),
+            EditError::Invalid(e) => f.write_str(e),
+            EditError::Io(e) => write!(f, "could not update the config file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+impl From<io::Error> for EditError {
+    fn from(e: io::Error) -> Self {
+        EditError::Io(e)
+    }
+}
+
+fn invalid(e: impl Display) -> EditError {
+    EditError::Invalid(e.to_string())
+}
+
+pub fn add_watch(path: &Path, mut watch: _DirConfig) -> Result<(), EditError> {
+    if !Path::new(&watch.directory).is_absolute() {
+        return Err(invalid("the directory has to be an absolute path"));
+    }
+    if !Path::new(&watch.directory).is_dir() {
+        return Err(invalid(format!("{} is not a directory", watch.directory)));
+    }
+    let name = watch
+        .name
+        .get_or_insert_with(|| watch.directory.clone())
+        .clone();
+
+    let mut doc = load(path)?;
+    if find_watch(&doc, &name).is_some() {
+        return Err(invalid(format!("a watch named {} already exists", name)));
+    }
+
+    // toml_edit can't serialize structs, the text toml writes for one is parsed again instead
+    let table = toml::to_string(&watch)
+        .map_err(invalid)?
+        .parse::<DocumentMut>()
+        .map_err(invalid)?
+        .as_table()
+        .clone();
+    match doc
+        .entry("watch")
+        .or_insert_with(|| Item::ArrayOfTables(ArrayOfTables::new()))
+    {
+        Item::ArrayOfTables(watches) => watches.push(table),
+        Item::Value(Value::Array(watches)) => watches.push(table.into_inline_table()),
+        _ => return Err(invalid("watch is not an array of tables")),
+    }
+    save(path, &doc)
+}
+
+pub fn remove_watch(path: &Path, name: &str) -> Result<(), EditError> {
+    let mut doc = load(path)?;
+    let i = find_watch(&doc, name).ok_or_else(|| EditError::NotFound(name.to_string()))?;
+    match doc.get_mut("watch") {
+        Some(Item::ArrayOfTables(watches)) => watches.remove(i),
+        Some(Item::Value(Value::Array(watches))) => {
+            watches.remove(i);
+        }
+        _ => {}
+    }
+    save(path, &doc)
+}
+
+/// TOML text of a key, or of the whole section without one
+pub fn get_key(path: &Path, scope: &str, key: Option<&str>) -> Result<Option<String>, EditError> {
+    let mut doc = load(path)?;
+    let section = section(&mut doc, scope)?;
+    match key {
+        None => Ok(Some(
+            section
+                .iter()
+                .filter_map(|(k, v)| Some(format!("{} = {}\n", k, bare(v)?)))
+                .collect(),
+        )),
+        Some(key) => {
+            check_key(scope, key)?;
+            Ok(section.get(key).and_then(bare))
+        }
+    }
+}
+
+/// A value without the whitespace & comments around it in the file
+fn bare(item: &Item) -> Option<String> {
+    let mut v = item.as_value()?.clone();
+    v.decor_mut().clear();
+    Some(v.to_string())
+}
+
+/// Sets a key from the words given on the command line, `None` unsets it
+pub fn set_key(
+    path: &Path,
+    scope: &str,
+    key: &str,
+    value: Option<Vec<String>>,
+) -> Result<(), EditError> {
+    check_key(scope, key)?;
+    let mut doc = load(path)?;
+    let section = section(&mut doc, scope)?;
+    match value {
+        Some(value) => replace(section, key, parse_value(key, value)?),
+        // ignore is required, an empty list is as unset as it gets
+        None if key == "ignore" => replace(section, key, Value::Array(Default::default())),
+        None if scope == "global" && ["poll_interval", "write_delay"].contains(&key) => {
+            return Err(invalid(format!("global {} can't be unset", key)));
+        }
+        None if key == "directory" => {
+            return Err(invalid("directory can't be unset"));
+        }
+        None => {
+            section.remove(key);
+        }
+    }
+    save(path, &doc)
+}
+
+/// Sets a key, keeping the spacing around it & a comment at the end of its line
+fn replace(section: &mut dyn TableLike, key: &str, mut value: Value) {
+    match section.get_mut(key) {
+        Some(item) => {
+            if let Some(old) = item.as_value() {
+                *value.decor_mut() = old.decor().clone();
+            }
+            *item = Item::Value(value);
+        }
+        None => {
+            section.insert(key, Item::Value(value));
+        }
+    }
+}
+
+fn check_key(scope: &str, key: &str) -> Result<(), EditError> {
+    let keys = if scope == "global" {
+        GLOBAL_KEYS
+    } else {
+        WATCH_KEYS
+    };
+    if !keys.contains(&key) {
+        return Err(invalid(format!(
+            "invalid key {}, expected one of: {}",
+            key,
+            keys.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+fn parse_value(key: &str, mut value: Vec<String>) -> Result<Value, EditError> {
+    match key {
+        "ignore" => return Ok(Value::Array(value.into_iter().collect())),
+        "commit_message" => return Ok(Value::from(value.join(" "))),
+        _ => {}
+    }
+    if value.len() != 1 {
+        return Err(invalid(format!("{} takes exactly one value", key)));
+    }
+    let v = value.remove(0);
+    match key {
+        "poll_interval" | "write_delay" => match v.parse::<i32>() {
+            Ok(n) if n > 0 => Ok(Value::from(i64::from(n))),
+            _ => Err(invalid(format!("{} expects a positive integer", key))),
+        },
+        "directory" if !Path::new(&v).is_absolute() => {
+            Err(invalid("the directory has to be an absolute path"))
+        }
+        // watcher & vcs are checked when the edited config is loaded
+        _ => Ok(Value::from(v)),
+    }
+}
+
+fn load(path: &Path) -> Result<DocumentMut, EditError> {
+    let text = fs::read_to_string(path)?;
+    text.parse()
+        .map_err(|e| invalid(format!("the config file is broken: {}", e)))
+}
+
+/// Index of a watch in the `watch` array, watches without a name go by their directory
+fn find_watch(doc: &DocumentMut, name: &str) -> Option<usize> {
+    let by_name = |w: &dyn TableLike| {
+        w.get("name")
+            .or_else(|| w.get("directory"))
+            .and_then(Item::as_str)
+            == Some(name)
+    };
+    match doc.get("watch") {
+        Some(Item::ArrayOfTables(watches)) => watches.iter().position(|w| by_name(w)),
+        Some(Item::Value(Value::Array(watches))) => watches
+            .iter()
+            .position(|w| w.as_inline_table().is_some_and(|w| by_name(w))),
+        _ => None,
+    }
+}
+
+/// The `global` table, or the table of the watch named `scope`
+fn section<'a>(doc: &'a mut DocumentMut, scope: &str) -> Result<&'a mut dyn TableLike, EditError> {
+    let section = if scope == "global" {
+        doc.get_mut("global").and_then(Item::as_table_like_mut)
+    } else {
+        let i = find_watch(doc, scope).ok_or_else(|| EditError::NotFound(scope.to_string()))?;
+        match doc.get_mut("watch") {
+            Some(Item::ArrayOfTables(watches)) => {
+                watches.get_mut(i).map(|t| t as &mut dyn TableLike)
+            }
+            Some(Item::Value(Value::Array(watches))) => watches
+                .get_mut(i)
+                .and_then(Value::as_inline_table_mut)
+                .map(|t| t as &mut dyn TableLike),
+            _ => None,
+        }
+    };
+    section.ok_or_else(|| invalid(format!("{} is not a table", scope)))
+}
+
+/// Checks that the edited config loads & swaps it in, keeping the previous version as a timestamped backup
+fn save(path: &Path, doc: &DocumentMut) -> Result<(), EditError> {
+    let text = doc.to_string();
+    CbakConfig::parse(&text, path.to_path_buf()).map_err(invalid)?;
+
+    // the new file keeps the owner & mode, whoever may edit the config now may do so afterwards
+    let meta = fs::metadata(path)?;
+    let tmp = with_suffix(path, ".tmp");
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let stamp = chrono::Local::now().format(BACKUP_TIME_FORMAT);
+    let written = write_file(&tmp, text.as_bytes(), meta.mode() & 0o7777, Some(&meta))
+        .and_then(|_| fs::copy(path, with_suffix(path, &format!(".{}.bak", stamp))))
+        // a rename within a directory is atomic, readers see either the old or the new file
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    rotate_backups(path);
+    Ok(())
+}
+
+/// Deletes all but the newest `BACKUPS` backups of the config file
+fn rotate_backups(path: &Path) {
+    let (Some(dir), Some(file)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let prefix = format!("{}.", file.to_string_lossy());
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Could not list backups of {}. {}", path.display(), e);
+            return;
+        }
+    };
+    let mut backups = entries
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter(|n| {
+            // only names this module writes, a hand made config.toml.bak is left alone
+            n.strip_prefix(&prefix)
+                .and_then(|n| n.strip_suffix(".bak"))
+                .is_some_and(|t| {
+                    chrono::NaiveDateTime::parse_from_str(t, BACKUP_TIME_FORMAT).is_ok()
+                })
+        })
+        .collect::<Vec<String>>();
+    backups.sort();
+    let old = backups.len().saturating_sub(BACKUPS);
+    for b in &backups[..old] {
+        if let Err(e) = fs::remove_file(dir.join(b)) {
+            warn!("Could not remove old backup {}. {}", b, e);
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    const CONFIG: &str = "# maintained by ops
+[global]
+ignore = []   # nothing yet
+poll_interval = 30
+write_delay   = 30 # seconds
+colour = \"blue\"
+";
+
+    /// A directory of its own for every test, removed afterwards
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cbakd-{}-{}", std::process::id(), test));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn config(&self, text: &str) -> PathBuf {
+            let path = self.0.join("config.toml");
+            fs::write(&path, text).unwrap();
+            path
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut names = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<String>>();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn watch(name: &str, directory: &Path) -> _DirConfig {
+        _DirConfig {
+            name: Some(name.to_string()),
+            directory: directory.to_string_lossy().into_owned(),
+            ignore: vec![],
+            poll_interval: None,
+            write_delay: None,
+            watcher: None,
+            vcs: None,
+            commit_message: None,
+            git_dir: None,
+        }
+    }
+
+    #[test]
+    fn replace_keeps_decor() {
+        let mut doc = CONFIG.parse::<DocumentMut>().unwrap();
+        let global = doc["global"].as_table_like_mut().unwrap();
+        replace(global, "write_delay", Value::from(5));
+        replace(
+            global,
+            "ignore",
+            Value::Array(["/tmp"].into_iter().collect()),
+        );
+        replace(global, "vcs", Value::from("git"));
+        assert_eq!(
+            doc.to_string(),
+            "# maintained by ops
+[global]
+ignore = [\"/tmp\"]   # nothing yet
+poll_interval = 30
+write_delay   = 5 # seconds
+colour = \"blue\"
+vcs = \"git\"
+"
+        );
+    }
+
+    #[test]
+    fn set_key_keeps_the_rest() {
+        let dir = TempDir::new("set_key");
+        let path = dir.config(CONFIG);
+        set_key(&path, "global", "poll_interval", Some(vec!["10".into()])).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            CONFIG.replace("poll_interval = 30", "poll_interval = 10")
+        );
+        assert_eq!(
+            get_key(&path, "global", Some("poll_interval")).unwrap(),
+            Some("10".into())
+        );
+        // the previous version is kept
+        assert_eq!(dir.files().len(), 2);
+
+        assert!(set_key(&path, "global", "write_delay", None).is_err());
+        assert!(set_key(&path, "global", "poll_interval", Some(vec!["0".into()])).is_err());
+        assert!(set_key(&path, "global", "colour", Some(vec!["red".into()])).is_err());
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("poll_interval = 10"));
+    }
+
+    #[test]
+    fn save_keeps_owner_and_mode() {
+        let dir = TempDir::new("owner");
+        let path = dir.config(CONFIG);
+        // only root may give a file away
+        let (uid, gid) = match unsafe { libc::getuid() } {
+            0 => (65534, 65534),
+            _ => unsafe { (libc::getuid(), libc::getgid()) },
+        };
+        std::os::unix::fs::chown(&path, Some(uid), Some(gid)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        // planted where the new file is written
+        std::os::unix::fs::symlink(dir.0.join("elsewhere"), with_suffix(&path, ".tmp")).unwrap();
+
+        set_key(&path, "global", "poll_interval", Some(vec!["10".into()])).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.is_file());
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+        assert_eq!(meta.mode() & 0o7777, 0o640);
+        assert!(!dir.0.join("elsewhere").exists());
+        assert!(!with_suffix(&path, ".tmp").exists());
+    }
+
+    #[test]
+    fn inline_watch_tables() {
+        let dir = TempDir::new("inline");
+        let text = format!(
+            "watch = [{{ name = \"a\", directory = {:?}, ignore = [] }}]\n{}",
+            dir.0, CONFIG
+        );
+        let path = dir.config(&text);
+
+        set_key(&path, "a", "write_delay", Some(vec!["5".into()])).unwrap();
+        assert_eq!(
+            get_key(&path, "a", Some("write_delay")).unwrap(),
+            Some("5".into())
+        );
+        let text = fs::read_to_string(&path).unwrap();
+        // set inside the inline table, the rest of the file is untouched
+        let (first, rest) = text.split_once('\n').unwrap();
+        assert!(first.starts_with("watch = [{ name = \"a\", "), "{}", text);
+        assert!(first.ends_with("write_delay = 5 }]"), "{}", text);
+        assert_eq!(rest, CONFIG);
+
+        add_watch(&path, watch("b", &dir.0)).unwrap();
+        let config = CbakConfig::read(&path).unwrap();
+        let names = config
+            .watch
+            .iter()
+            .map(|w| w.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+        // still an inline array, not a mix with [[watch]] tables
+        assert!(!fs::read_to_string(&path).unwrap().contains("[[watch]]"));
+
+        remove_watch(&path, "a").unwrap();
+        assert!(matches!(
+            remove_watch(&path, "a"),
+            Err(EditError::NotFound(_))
+        ));
+        assert_eq!(CbakConfig::read(&path).unwrap().watch[0].name, "b");
+    }
+
+    #[test]
+    fn broken_edits_leave_the_file_alone() {
+        let dir = TempDir::new("broken");
+        let path = dir.config(CONFIG);
+        assert!(add_watch(&path, watch("a", Path::new("relative"))).is_err());
+        assert!(set_key(&path, "nope", "ignore", None).is_err());
+        assert!(set_key(&path, "global", "watcher", Some(vec!["fanotify".into()])).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), CONFIG);
+        assert_eq!(dir.files(), ["config.toml"]);
+    }
+
+    #[test]
+    fn rotate_backups_keeps_the_newest() {
+        let dir = TempDir::new("rotate");
+        let path = dir.config(CONFIG);
+        let stamps = (0..BACKUPS + 3)
+            .map(|i| format!("config.toml.20221020-1400{:02}.000.bak", i))
+            .collect::<Vec<String>>();
+        for s in &stamps {
+            fs::write(dir.0.join(s), "").unwrap();
+        }
+        // not written by cbakd
+        fs::write(dir.0.join("config.toml.bak"), "").unwrap();
+        fs::write(dir.0.join("config.toml.old.bak"), "").unwrap();
+
+        rotate_backups(&path);
+        let mut expected = stamps[3..].to_vec();
+        expected
+            .extend(["config.toml", "config.toml.bak", "config.toml.old.bak"].map(String::from));
+        expected.sort();
+        assert_eq!(dir.files(), expected);
+    }
+}
@@ -0,0 +1,301 @@
+//! Read-only access to the commits of a watch.
+//! This always goes through libgit2, whichever backend the worker commits with.
+
+use std::{error::Error, path::Path};
+
+use cbak_config::DirConfig;
+use cbak_proto::{ChangeKind, ChangedFile, CommitInfo, TagInfo};
+use chrono::{DateTime, Local, TimeZone};
+use git2::{Commit, Delta, Diff, DiffFindOptions, DiffOptions, Patch, Repository, Sort};
+
+use crate::{
+    timespec,
+    watcher::{self, matches, FileChanges},
+};
+
+/// Which commits `log` returns
+#[derive(Default)]
+pub struct LogFilter {
+    /// Relative to the watched directory
+    pub path: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub limit: Option<usize>,
+}
+
+/// Turns a path given by the user into one relative to the watched directory
+pub fn relative_path(config: &DirConfig, path: &str) -> Result<String, String> {
+    let p = Path::new(path);
+    if !p.is_absolute() {
+        return Ok(path.trim_start_matches("./").to_string());
+    }
+    p.strip_prefix(&config.directory)
+        .map(|p| p.to_string_lossy().into_owned())
+        .map_err(|_| format!("{} is not inside {}", path, config.directory))
+}
+
+/// Opens the repository of a watch with the watched directory as its work tree
+pub fn open(config: &DirConfig) -> Result<Repository, git2::Error> {
+    let repo = Repository::open(&config.git_dir)?;
+    repo.set_workdir(Path::new(&config.directory), false)?;
+    Ok(repo)
+}
+
+/// Finds the commit a revision or tag refers to.
+/// Anything git doesn't know is tried as a time, which picks the last commit made before it,
+/// or `None` if the time is older than the history.
+pub fn resolve<'r>(repo: &'r Repository, rev: &str) -> Result<Option<Commit<'r>>, Box<dyn Error>> {
+    if let Ok(obj) = repo.revparse_single(rev) {
+        return Ok(Some(obj.peel_to_commit()?));
+    }
+    let time = timespec::parse(rev)
+        .map_err(|e| format!("{} is neither a revision nor a time ({})", rev, e))?;
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TIME)?;
+    walk.push_head()?;
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        if commit.time().seconds() <= time.timestamp() {
+            return Ok(Some(commit));
+        }
+    }
+    Ok(None)
+}
+
+/// Unified diff between two revisions, `to: None` compares against the files on disk.
+/// Without `from` the last commit is used.
+pub fn diff(
+    config: &DirConfig,
+    from: Option<&str>,
+    to: Option<&str>,
+    path: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let repo = open(config)?;
+    // a time before the first commit compares against an empty tree
+    let old = resolve(&repo, from.unwrap_or("HEAD"))?
+        .map(|c| c.tree())
+        .transpose()?;
+
+    let mut opts = DiffOptions::new();
+    if let Some(p) = path {
+        opts.pathspec(p);
+    }
+    let mut diff = match to {
+        Some(rev) => {
+            let new = resolve(&repo, rev)?.map(|c| c.tree()).transpose()?;
+            repo.diff_tree_to_tree(old.as_ref(), new.as_ref(), Some(&mut opts))?
+        }
+        None => {
+            opts.include_untracked(true)
+                .recurse_untracked_dirs(true)
+                .show_untracked_content(true);
+            repo.diff_tree_to_workdir(old.as_ref(), Some(&mut opts))?
+        }
+    };
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    let root = Path::new(&config.directory);
+    let mut out = String::new();
+    for (i, delta) in diff.deltas().enumerate() {
+        // files on disk that the worker would never commit
+        let file = delta.new_file().path().or_else(|| delta.old_file().path());
+        if file.is_some_and(|f| matches(root.join(f).to_str().unwrap_or(""), &config.ignore)) {
+            continue;
+        }
+        if let Some(mut patch) = Patch::from_diff(&diff, i)? {
+            out.push_str(&String::from_utf8_lossy(&patch.to_buf()?));
+        }
+    }
+    Ok(out)
+}
+
+/// Changes on disk since the last commit, the way a watcher would have reported them
+pub fn uncommitted(config: &DirConfig) -> Result<Vec<FileChanges>, Box<dyn Error>> {
+    let repo = open(config)?;
+    let head = match repo.head() {
+        Ok(h) => Some(h.peel_to_tree()?),
+        Err(_) => None,
+    };
+    let mut opts = DiffOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    let mut diff = repo.diff_tree_to_workdir(head.as_ref(), Some(&mut opts))?;
+    diff.find_similar(Some(
+        DiffFindOptions::new().renames(true).for_untracked(true),
+    ))?;
+
+    let root = Path::new(&config.directory);
+    Ok(diff
+        .deltas()
+        .filter_map(|d| {
+            let new = root.join(d.new_file().path()?);
+            let old = root.join(d.old_file().path()?);
+            if matches(new.to_str().unwrap_or(""), &config.ignore) {
+                return None;
+            }
+            Some(match d.status() {
+                Delta::Added | Delta::Untracked => FileChanges::Created(new),
+                Delta::Deleted => FileChanges::Deleted(old),
+                Delta::Renamed => FileChanges::Renamed(old, new),
+                _ => FileChanges::Modified(new),
+            })
+        })
+        .collect())
+}
+
+/// Changes as reported by a watcher, with files it thought were new but that are in the last commit marked as
+/// modified & the other way around, see `watcher::reclassify`
+pub fn against_head(config: &DirConfig, changes: Vec<FileChanges>) -> Vec<FileChanges> {
+    let Ok(repo) = open(config) else {
+        return changes;
+    };
+    // nothing is committed yet, everything is new
+    let Some(tree) = repo.head().ok().and_then(|h| h.peel_to_tree().ok()) else {
+        return watcher::reclassify(changes, |_| false);
+    };
+    let root = Path::new(&config.directory);
+    watcher::reclassify(changes, |p| {
+        p.strip_prefix(root)
+            .is_ok_and(|rel| tree.get_path(rel).is_ok())
+    })
+}
+
+/// Time & id of the last commit, `None` if there is none yet
+pub fn head(config: &DirConfig) -> Option<(DateTime<Local>, String)> {
+    let repo = open(config).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    let time = Local.timestamp_opt(commit.time().seconds(), 0).single()?;
+    Some((time, commit.id().to_string()))
+}
+
+/// Contents of a file at a revision or time
+pub fn show(config: &DirConfig, rev: &str, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let repo = open(config)?;
+    let commit =
+        resolve(&repo, rev)?.ok_or_else(|| format!("nothing was committed before {}", rev))?;
+    let entry = commit
+        .tree()?
+        .get_path(Path::new(path))
+        .map_err(|_| format!("{} didn't exist in {:.8}", path, commit.id()))?;
+    let blob = entry
+        .to_object(&repo)?
+        .into_blob()
+        .map_err(|_| format!("{} is a directory", path))?;
+    Ok(blob.content().to_vec())
+}
+
+/// Tags pointing at commits, newest first.
+/// Annotated tags go by when they were made, lightweight ones by the time of their commit.
+pub fn tags(config: &DirConfig) -> Result<Vec<TagInfo>, Box<dyn Error>> {
+    let repo = open(config)?;
+    let mut tags = vec![];
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let obj = repo.revparse_single(&format!("refs/tags/{}", name))?;
+        let commit = match obj.peel_to_commit() {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let (time, message) = match obj.as_tag() {
+            Some(t) => (
+                t.tagger()
+                    .map(|s| s.when().seconds())
+                    .unwrap_or_else(|| commit.time().seconds()),
+                t.message().unwrap_or("").trim_end().to_string(),
+            ),
+            None => (commit.time().seconds(), String::new()),
+        };
+        tags.push((
+            time,
+            TagInfo {
+                name: name.to_string(),
+                commit: commit.id().to_string(),
+                time: Local
+                    .timestamp_opt(time, 0)
+                    .single()
+                    .unwrap_or_else(Local::now)
+                    .to_rfc3339(),
+                message,
+            },
+        ));
+    }
+    tags.sort_by_key(|t| std::cmp::Reverse(t.0));
+    Ok(tags.into_iter().map(|(_, t)| t).collect())
+}
+
+/// Commits of a watch, newest first
+pub fn log(config: &DirConfig, filter: &LogFilter) -> Result<Vec<CommitInfo>, Box<dyn Error>> {
+    let repo = open(config)?;
+    // nothing has been committed yet
+    if repo.head().is_err() {
+        return Ok(vec![]);
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TIME)?;
+    walk.push_head()?;
+
+    let mut commits = vec![];
+    for id in walk {
+        if filter.limit.is_some_and(|n| commits.len() >= n) {
+            break;
+        }
+        let commit = repo.find_commit(id?)?;
+        let time = Local
+            .timestamp_opt(commit.time().seconds(), 0)
+            .single()
+            .unwrap_or_else(Local::now);
+        if filter.until.is_some_and(|u| time > u) {
+            continue;
+        }
+        if filter.since.is_some_and(|s| time < s) {
+            // commits are sorted by time, everything after this is older
+            break;
+        }
+
+        let mut opts = DiffOptions::new();
+        if let Some(p) = &filter.path {
+            opts.pathspec(p);
+        }
+        let parent = match commit.parent(0) {
+            Ok(p) => Some(p.tree()?),
+            Err(_) => None,
+        };
+        let mut diff =
+            repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut opts))?;
+        if diff.deltas().len() == 0 {
+            continue;
+        }
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+        commits.push(CommitInfo {
+            id: commit.id().to_string(),
+            time: time.to_rfc3339(),
+            author: commit.author().name().unwrap_or("").to_string(),
+            message: commit.message().unwrap_or("").trim_end().to_string(),
+            files: changed_files(&diff),
+        });
+    }
+    Ok(commits)
+}
+
+fn changed_files(diff: &Diff) -> Vec<ChangedFile> {
+    let path = |f: git2::DiffFile| f.path().map(|p| p.to_string_lossy().into_owned());
+    diff.deltas()
+        .filter_map(|d| {
+            let change = match d.status() {
+                Delta::Added | Delta::Copied => ChangeKind::Added,
+                Delta::Deleted => ChangeKind::Deleted,
+                Delta::Renamed => ChangeKind::Renamed,
+                _ => ChangeKind::Modified,
+            };
+            Some(ChangedFile {
+                change,
+                path: path(d.new_file()).or_else(|| path(d.old_file()))?,
+                old_path: match change {
+                    ChangeKind::Renamed => path(d.old_file()),
+                    _ => None,
+                },
+            })
+        })
+        .collect()
+}
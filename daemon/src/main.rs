@@ -1,55 +1,245 @@
-///
-use core::time;
 use std::{
-    io::{self, BufRead, BufReader, Write},
+    fs, io,
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsRawFd, FromRawFd, IntoRawFd},
+        net::UnixStream,
+    },
     path::{Path, PathBuf},
-    process::Command,
     sync::{
-        atomic::AtomicUsize,
-        mpsc::{self, Receiver, TryRecvError},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
     },
-    time::{Duration, SystemTime},
+    time::{Duration, Instant},
 };
 
-use fancy_regex::Regex;
-use interprocess::local_socket::{LocalSocketListener, LocalSocketStream, NameTypeSupport};
-use log::{debug, error, info, trace, warn};
-use rayon::prelude::*;
-mod config;
+use cbak_proto::{
+    CommitRef, Connection, ErrorKind, ProtoError, Request, Response, WatchError, WatchInfo,
+    WatchStatus, WorkerState, MAX_REQUEST_LINE, PROTOCOL_VERSION,
+};
+use clap::{arg, command, value_parser};
+use interprocess::local_socket::{LocalSocketListener, LocalSocketStream};
+use log::{debug, error, info, warn, Level, LevelFilter};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use supervisor::Supervisor;
+use watcher::{FileChanges, Wake, WorkerMsg};
+mod edit;
+mod history;
+mod message;
+mod restore;
+mod state;
+mod supervisor;
+mod timespec;
+mod vcs;
+mod watcher;
 
-static GLOBAL_THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// How long a client waits for a worker to finish a restore or a manual commit.
+/// A shutdown stops the wait right away, this & `SHUTDOWN_TIMEOUT` stay below `TimeoutStopSec` of the service anyway
+const WORKER_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Debug)]
-enum FileChanges<'a> {
-    File(Vec<&'a PathBuf>),
-    Modify(Vec<&'a PathBuf>),
-}
+/// How often waits on clients & workers look whether cbakd is shutting down
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// How long workers get to finish a commit when cbakd is asked to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
-/// Holds all the contents of a directory
-/// contents is the pooled contents of all of the subdirs & the root directory (excluding dirs)
-#[derive(Debug)]
-struct DirContents {
-    root: PathBuf,
-    subdirs: Vec<PathBuf>,
-    contents: Vec<PathBuf>,
+/// How long a client gets to send a request once the previous one is answered, connections are served on the main
+/// thread, a client that doesn't send anything mustn't hold up everything else for longer than this
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client gets to take each part of a response off the socket
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long one connection may stay open, a client sending a request every few seconds would hold up signals forever
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Set by the signal thread on SIGTERM & SIGINT, so the connection being served ends before the event comes up
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Everything the main thread reacts to
+enum Event {
+    Connection(LocalSocketStream),
+    Signal(i32),
+    WorkerExited(supervisor::Exit),
 }
 
-fn init_logger() -> Result<(), fern::InitError> {
+/// Under systemd (`JOURNAL_STREAM` is set) lines get a priority prefix instead of a timestamp, journald adds its own.
+/// `foreground` keeps the terminal format anyway.
+fn init_logger(level: LevelFilter, foreground: bool) -> Result<(), fern::InitError> {
+    let journal = !foreground && std::env::var_os("JOURNAL_STREAM").is_some();
     fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ))
+        .format(move |out, message, record| {
+            if journal {
+                // sd-daemon(3) priorities
+                let priority = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                out.finish(format_args!(
+                    "<{}>[{}] {}",
+                    priority,
+                    record.target(),
+                    message
+                ))
+            } else {
+                out.finish(format_args!(
+                    "{}[{}][{}] {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    record.level(),
+                    message
+                ))
+            }
         })
+        .level(level)
         .chain(std::io::stdout())
         .apply()?;
     Ok(())
 }
 
+/// A client connection whose reads fail once `deadline` has passed, however slowly the bytes trickle in
+struct TimedStream {
+    stream: UnixStream,
+    deadline: Instant,
+}
+
+impl TimedStream {
+    fn new(conn: LocalSocketStream) -> io::Result<Self> {
+        // SAFETY: the fd is owned by the LocalSocketStream, into_raw_fd hands that ownership over
+        let stream = unsafe { UnixStream::from_raw_fd(conn.into_raw_fd()) };
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Self {
+            stream,
+            deadline: Instant::now() + REQUEST_TIMEOUT,
+        })
+    }
+}
+
+/// User & primary group of the process on the other end of a socket
+fn peer_credentials(stream: &UnixStream) -> io::Result<(u32, u32)> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred & len are valid for writes of the size passed in len
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((cred.uid, cred.gid))
+}
+
+/// Whether a user could write the config file themselves. The socket is open to every local user, but only those
+/// may change what cbakd does through it. Supplementary groups of the peer aren't known, only its primary group counts
+fn may_edit(config_file: &Path, uid: u32, gid: u32) -> bool {
+    // SAFETY: getuid has no preconditions & can't fail
+    if uid == 0 || uid == unsafe { libc::getuid() } {
+        return true;
+    }
+    let Ok(meta) = fs::metadata(config_file) else {
+        return false;
+    };
+    let mode = meta.mode();
+    (meta.uid() == uid && mode & 0o200 != 0)
+        || (meta.gid() == gid && mode & 0o020 != 0)
+        || mode & 0o002 != 0
+}
+
+/// Requests only users who may edit the config can make, see `may_edit`.
+/// Besides config changes that's reading the config, which may not be readable for everyone,
+/// checking it, which looks at the paths in it as root, anything that reads committed files,
+/// e.g. a copy of /etc/shadow, & anything that changes the history
+fn needs_write_access(req: &Request) -> bool {
+    matches!(
+        req,
+        Request::Reload
+            | Request::AddWatch { .. }
+            | Request::RemoveWatch { .. }
+            | Request::GetKey { .. }
+            | Request::SetKey { .. }
+            | Request::CheckConfig
+            | Request::Log { .. }
+            | Request::Diff { .. }
+            | Request::Show { .. }
+            | Request::Restore { .. }
+            | Request::Commit { .. }
+            | Request::Tag { .. }
+            | Request::Tags { .. }
+            | Request::Pause { .. }
+            | Request::Resume { .. }
+    )
+}
+
+impl io::Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let left = self.deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "client is idle"));
+            }
+            if STOPPING.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "cbakd is shutting down",
+                ));
+            }
+            // in slices, so a signal doesn't wait for the client
+            self.stream.set_read_timeout(Some(left.min(STOP_POLL)))?;
+            match self.stream.read(buf) {
+                // what a read timeout looks like on unix
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+        }
+    }
+}
+
+impl io::Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// The reply of a worker to a request passed on to it, giving up after `WORKER_TIMEOUT` or when cbakd is stopping.
+/// The worker finishes what it's doing either way
+fn wait_for_worker<T>(rx: &Receiver<Result<T, String>>) -> Result<T, String> {
+    let deadline = Instant::now() + WORKER_TIMEOUT;
+    loop {
+        match rx.recv_timeout(STOP_POLL) {
+            Ok(r) => return r,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("the worker didn't answer".to_string())
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) if STOPPING.load(Ordering::Relaxed) => {
+                return Err("cbakd is shutting down".to_string())
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() >= deadline => {
+                return Err("the worker didn't answer".to_string())
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+    }
+}
+
 fn handle_socket_error(conn: io::Result<LocalSocketStream>) -> Option<LocalSocketStream> {
     match conn {
         Ok(c) => Some(c),
@@ -61,493 +251,976 @@ fn handle_socket_error(conn: io::Result<LocalSocketStream>) -> Option<LocalSocke
     }
 }
 
-//TODO: More logging?
-fn main() {
-    match init_logger() {
-        Ok(_) => {}
-        Err(_) => {
-            eprintln!("Failed to init logger.");
-            return;
-        }
-    };
+/// The running workers & the configuration they were started from
+struct Daemon {
+    config: cbak_config::CbakConfig,
+    workers: Supervisor,
+}
 
-    let mut config = match config::CbakConfig::new() {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Could not load config.");
-            debug!("{}", e);
-            return;
+impl Daemon {
+    fn spawn_workers(&mut self) {
+        // for every [[watch]] block in the config, spawn a thread to watch that dir.
+        for i in self.config.watch.clone() {
+            self.workers.start(i);
         }
-    };
+    }
 
-    let mut handles = vec![];
-    let mut channels = vec![];
-
-    for i in config.watch {
-        if !Path::new(&i.directory).join(".git/").exists() {
-            match Command::new("git")
-                .arg("init")
-                .current_dir(&i.directory)
-                .output()
-            {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Could not execute git init. Do you have git installed?");
-                    debug!("{}", e);
-                    return;
+    /// Loads the config file again & only touches the watches that changed in it.
+    /// Nothing is stopped if the new config doesn't load, the workers keep running with the previous one
+    fn reload(&mut self) -> Result<Response, Response> {
+        let path = self.config.config_file_path.to_string_lossy().into_owned();
+        // load would write the defaults over a deleted file, which has no watches
+        if !self.config.config_file_path.exists() {
+            error!("Could not reload, {} doesn't exist anymore.", path);
+            return Err(Response::Error {
+                kind: ErrorKind::Internal,
+                message: format!(
+                    "{} doesn't exist anymore, keeping the previous config",
+                    path
+                ),
+            });
+        }
+        let config = match cbak_config::CbakConfig::load(&self.config.config_file_path) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Could not reload config, keeping the previous one.");
+                let (kind, problems) = match e.downcast_ref::<cbak_config::InvalidConfig>() {
+                    Some(invalid) => (
+                        ErrorKind::InvalidConfig,
+                        invalid.problems.iter().map(|p| p.in_file(&path)).collect(),
+                    ),
+                    None => (ErrorKind::Internal, vec![e.to_string()]),
+                };
+                for p in &problems {
+                    error!("{}", p);
                 }
+                return Err(Response::Error {
+                    kind,
+                    message: format!("keeping the previous config\n{}", problems.join("\n")),
+                });
+            }
+        };
+        for w in &config.warnings {
+            warn!("{}", w.in_file(&path));
+        }
+        self.workers.apply(&config.watch);
+        self.config = config;
+        Ok(Response::Ok)
+    }
+
+    /// Applies an edit to the config file, then reloads if asked to
+    fn edit(
+        &mut self,
+        reload: bool,
+        f: impl FnOnce(&std::path::Path) -> Result<(), edit::EditError>,
+    ) -> Response {
+        if let Err(e) = f(&self.config.config_file_path) {
+            return Response::Error {
+                kind: e.kind(),
+                message: e.to_string(),
             };
         }
-        let (tx, rx) = mpsc::channel::<u8>();
-        let builder = std::thread::Builder::new().name(i.directory.clone());
-        handles.push(builder.spawn(move || run(i, rx)));
-        GLOBAL_THREAD_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        channels.push(tx);
-        debug!("Spawned one worker thread.");
-        trace!("Thread count at {:?}", GLOBAL_THREAD_COUNT);
+        info!("Updated {}", self.config.config_file_path.display());
+        if reload {
+            return self.handle(Request::Reload);
+        }
+        Response::Ok
     }
 
-    loop {
-        //Listen for cli updates on main thread
-        let name = {
-            use NameTypeSupport::*;
-            match NameTypeSupport::query() {
-                OnlyPaths => "/tmp/cbak.sock",
-                OnlyNamespaced | Both => "@cbak.sock",
-            }
-        };
+    fn find_watch(&self, name: &str) -> Result<&cbak_config::DirConfig, Response> {
+        self.config
+            .watch
+            .iter()
+            .find(|w| w.name == name)
+            .ok_or_else(|| Response::Error {
+                kind: ErrorKind::NotFound,
+                message: format!("no watch named {}", name),
+            })
+    }
+
+    /// Channel of the worker of a watch
+    fn worker(&self, name: &str) -> Result<&Sender<WorkerMsg>, Response> {
+        self.find_watch(name)?;
+        self.workers.sender(name).ok_or_else(|| Response::Error {
+            kind: ErrorKind::Internal,
+            message: format!("the worker of {} isn't running", name),
+        })
+    }
+
+    /// Hands a message to the worker of a watch
+    fn send(&self, name: &str, msg: WorkerMsg) -> Result<(), Response> {
+        self.worker(name)?.send(msg).map_err(|_| Response::Error {
+            kind: ErrorKind::Internal,
+            message: format!("the worker of {} isn't running", name),
+        })
+    }
 
-        let listener = match LocalSocketListener::bind(name) {
-            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
-                error!(
-                    "Error: Could not bind to socket {}. Please check if socket is in use.",
-                    name
+    fn pause(&self, name: &str, seconds: Option<u64>) -> Result<Response, Response> {
+        let duration = seconds.map(Duration::from_secs);
+        self.send(name, WorkerMsg::Pause { duration })?;
+        // recorded here rather than by the worker, so the next status request already sees it
+        state::paused(
+            name,
+            duration
+                .and_then(|d| chrono::Duration::from_std(d).ok())
+                .map(|d| chrono::Local::now() + d),
+        );
+        if let Some(d) = duration {
+            let tx = self.worker(name)?.clone();
+            let timer = std::thread::Builder::new()
+                .name(format!("{}-pause", name))
+                .spawn(move || {
+                    std::thread::sleep(d);
+                    // the worker may be gone by now
+                    let _ = tx.send(WorkerMsg::PauseExpired);
+                });
+            if let Err(e) = timer {
+                warn!(
+                    "Could not start the pause timer of {}, it stays paused until resumed. {}",
+                    name, e
                 );
-                debug!("{}", err);
-                return;
-            }
-            Err(err) => {
-                error!("Socket Error.");
-                debug!("{}", err);
-                return;
             }
-            Ok(x) => x,
-        };
+        }
+        Ok(Response::Ok)
+    }
 
-        info!("Bound to socket {}.", name);
-
-        //The control sceme will be as follows
-        //0000_0000 = no flags
-        //0000_0001 = ack # REMOVED
-        //0000_0010 = config update
-        //0000_0100 = request config path
-        // -- further bits reserved
-        let mut buf = String::new();
-        for conn in listener.incoming().filter_map(handle_socket_error) {
-            buf.clear();
-            let mut conn = BufReader::new(conn);
-
-            match conn.read_line(&mut buf) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Could not read from the socket. {}", e.kind());
-                    continue;
-                }
-            };
+    fn resume(&self, name: &str, commit: bool) -> Result<Response, Response> {
+        let paused = state::snapshot()
+            .get(name)
+            .is_some_and(|s| s.state == WorkerState::Paused);
+        if !paused {
+            self.find_watch(name)?;
+            return Err(Response::Error {
+                kind: ErrorKind::BadRequest,
+                message: format!("{} isn't paused", name),
+            });
+        }
+        self.send(name, WorkerMsg::Resume { commit })?;
+        state::resumed(name);
+        Ok(Response::Ok)
+    }
 
-            let b1 = buf.trim().as_bytes().first().unwrap_or(&0);
-            //if b1 & 0b0000_0001 == 0b0000_0001 {
-            //    println!("w");
-            //}
-            if b1 & 0b0000_0010 == 0b0000_0010 {
-                channels.iter().for_each(|f| match f.send(0) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Could not write to socket. Retrying.");
-                        debug!("{}", e);
-                        std::thread::sleep(Duration::from_millis(500));
-                        match f.send(0) {
-                            Ok(x) => x,
-                            Err(e) => {
-                                error!("Failed after retry.");
-                                debug!("{}", e);
-                                return;
-                            }
-                        };
-                    }
-                });
+    fn commit(
+        &self,
+        name: &str,
+        message: String,
+        author: Option<String>,
+    ) -> Result<Response, Response> {
+        let bad_request = |message: String| Response::Error {
+            kind: ErrorKind::BadRequest,
+            message,
+        };
+        if message.trim().is_empty() {
+            return Err(bad_request("the commit message is empty".to_string()));
+        }
+        let author = author
+            .map(|a| a.parse::<vcs::Author>())
+            .transpose()
+            .map_err(bad_request)?;
 
-                while GLOBAL_THREAD_COUNT.load(std::sync::atomic::Ordering::SeqCst) != 0 {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                }
+        let (tx, rx) = mpsc::channel();
+        self.send(
+            name,
+            WorkerMsg::Commit {
+                message,
+                author,
+                reply: tx,
+            },
+        )?;
+        match wait_for_worker(&rx) {
+            Ok(commit) => Ok(Response::Committed { commit }),
+            Err(e) => Err(Response::Error {
+                kind: ErrorKind::Internal,
+                message: format!("could not commit {}: {}", name, e),
+            }),
+        }
+    }
 
-                //respawn all threads with new config
-                config = match config::CbakConfig::new() {
-                    Ok(c) => c,
-                    Err(e) => {
-                        error!("Could not load config.");
-                        debug!("{}", e);
-                        return;
-                    }
-                };
+    fn tag(&self, name: &str, tag: String, message: Option<String>) -> Result<Response, Response> {
+        if !git2::Reference::is_valid_name(&format!("refs/tags/{}", tag)) {
+            return Err(Response::Error {
+                kind: ErrorKind::BadRequest,
+                message: format!("{:?} isn't a valid tag name", tag),
+            });
+        }
+        let (tx, rx) = mpsc::channel();
+        self.send(
+            name,
+            WorkerMsg::Tag {
+                message: message.unwrap_or_else(|| tag.clone()),
+                name: tag,
+                reply: tx,
+            },
+        )?;
+        match wait_for_worker(&rx) {
+            Ok(commit) => Ok(Response::Tagged { commit }),
+            Err(e) => Err(Response::Error {
+                kind: ErrorKind::BadRequest,
+                message: format!("could not tag {}: {}", name, e),
+            }),
+        }
+    }
 
-                handles.clear();
-                channels.clear();
-
-                // for every [[watch]] block in the config, spawn a thread to watch that dir.
-                for i in config.watch {
-                    if !Path::new(&i.directory).join(".git/").exists() {
-                        match Command::new("git")
-                            .arg("init")
-                            .current_dir(&i.directory)
-                            .output()
-                        {
-                            Ok(_) => {}
-                            Err(e) => {
-                                error!("Could not execute git init. Do you have git installed?");
-                                debug!("{}", e);
-                                return;
-                            }
-                        };
-                    }
-                    let (tx, rx) = mpsc::channel::<u8>();
-                    let builder = std::thread::Builder::new().name(i.directory.clone());
-                    handles.push(builder.spawn(move || run(i, rx)));
-                    GLOBAL_THREAD_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                    channels.push(tx);
-                }
-            }
-            if b1 & 0b0000_0100 == 0b0000_0100 {
-                match conn.get_mut().write_all(
-                    format!("{}\n", config.config_file_path.to_str().unwrap_or("")).as_bytes(),
-                ) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Could not write to socket. Retrying.");
-                        debug!("{}", e);
-                        std::thread::sleep(Duration::from_millis(500));
-                        match conn.get_mut().write_all(
-                            format!("{}\n", config.config_file_path.to_str().unwrap_or(""))
-                                .as_bytes(),
-                        ) {
-                            Ok(x) => x,
-                            Err(e) => {
-                                error!("Could not write to socket after retry.");
-                                debug!("{}", e);
-                                return;
-                            }
-                        }
-                    }
-                };
-            }
-            buf.clear();
+    fn tags(&self, name: &str) -> Result<Response, Response> {
+        let config = self.find_watch(name)?;
+        match history::tags(config) {
+            Ok(tags) => Ok(Response::Tags { tags }),
+            Err(e) => Err(Response::Error {
+                kind: ErrorKind::Internal,
+                message: format!("could not read tags of {}: {}", name, e),
+            }),
         }
     }
-}
 
-fn run(config: config::DirConfig, rx: Receiver<u8>) {
-    // main watch loop
-    loop {
-        let files = match get_all_files_filtered(Path::new(&config.directory), &config.ignore) {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Could not get filles. {}", e.kind());
-                debug!("{}", e);
-                continue;
-            }
+    fn log(
+        &self,
+        name: &str,
+        path: Option<String>,
+        since: Option<String>,
+        until: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Response, Response> {
+        let config = self.find_watch(name)?;
+        let bad_request = |message: String| Response::Error {
+            kind: ErrorKind::BadRequest,
+            message,
         };
+        let filter = history::LogFilter {
+            path: path
+                .map(|p| history::relative_path(config, &p))
+                .transpose()
+                .map_err(bad_request)?,
+            since: since
+                .map(|t| timespec::parse(&t))
+                .transpose()
+                .map_err(bad_request)?,
+            until: until
+                .map(|t| timespec::parse(&t))
+                .transpose()
+                .map_err(bad_request)?,
+            limit,
+        };
+        match history::log(config, &filter) {
+            Ok(commits) => Ok(Response::Log { commits }),
+            Err(e) => Err(Response::Error {
+                kind: ErrorKind::Internal,
+                message: format!("could not read history of {}: {}", name, e),
+            }),
+        }
+    }
 
-        let _res = wait_until_changed(&files, config.poll_interval, config.write_delay, &rx)
-            .unwrap_or(Some(FileChanges::File(vec![])));
+    fn diff(
+        &self,
+        name: &str,
+        from: Option<String>,
+        to: Option<String>,
+        path: Option<String>,
+    ) -> Result<Response, Response> {
+        let config = self.find_watch(name)?;
+        let path = path
+            .map(|p| history::relative_path(config, &p))
+            .transpose()
+            .map_err(|message| Response::Error {
+                kind: ErrorKind::BadRequest,
+                message,
+            })?;
+        match history::diff(config, from.as_deref(), to.as_deref(), path.as_deref()) {
+            Ok(patch) => Ok(Response::Diff { patch }),
+            Err(e) => Err(Response::Error {
+                kind: ErrorKind::BadRequest,
+                message: format!("could not diff {}: {}", name, e),
+            }),
+        }
+    }
 
-        let res = match _res {
-            Some(r) => r,
-            None => {
-                warn!(
-                    "Terminating thread {}",
-                    std::thread::current().name().unwrap_or("")
-                );
-                GLOBAL_THREAD_COUNT.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
-                return;
-            }
+    fn restore(
+        &self,
+        name: &str,
+        rev: String,
+        paths: Vec<String>,
+        dry_run: bool,
+    ) -> Result<Response, Response> {
+        let config = self.find_watch(name)?;
+        let paths = paths
+            .iter()
+            .map(|p| history::relative_path(config, p))
+            .collect::<Result<Vec<String>, String>>()
+            .map_err(|message| Response::Error {
+                kind: ErrorKind::BadRequest,
+                message,
+            })?;
+
+        let restored = if dry_run {
+            restore::plan(config, &rev, &paths).map_err(|e| e.to_string())
+        } else {
+            // the worker does the writing, so it can't commit in the middle of it
+            let (tx, rx) = mpsc::channel();
+            self.send(
+                name,
+                WorkerMsg::Restore {
+                    rev,
+                    paths,
+                    reply: tx,
+                },
+            )?;
+            wait_for_worker(&rx)
         };
+        match restored {
+            Ok(r) => Ok(Response::Restored {
+                from: r.from,
+                files: r.files,
+                commit: r.commit,
+            }),
+            Err(e) => Err(Response::Error {
+                kind: ErrorKind::BadRequest,
+                message: format!("could not restore {}: {}", name, e),
+            }),
+        }
+    }
 
-        match Command::new("git")
-            .arg("add")
-            .arg("-A")
-            .current_dir(&config.directory)
-            .output()
-        {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Could not run git add. Do you have git installed?");
-                debug!("{}", e);
-                return;
-            }
+    fn show(&self, name: &str, rev: &str, path: &str) -> Result<Vec<u8>, Response> {
+        let config = self.find_watch(name)?;
+        let bad_request = |message: String| Response::Error {
+            kind: ErrorKind::BadRequest,
+            message,
         };
+        let path = history::relative_path(config, path).map_err(bad_request)?;
+        history::show(config, rev, &path)
+            .map_err(|e| bad_request(format!("could not show {}: {}", path, e)))
+    }
 
-        Command::new("git")
-            .arg("rm")
-            .arg("-f")
-            .arg("--cached")
-            .args(
-                get_all_files_nfiltered(Path::new(&config.directory), &config.ignore)
-                    .unwrap()
-                    .contents
-                    .iter()
-                    .map(|i| {
-                        i.strip_prefix(&config.directory)
-                            .unwrap()
-                            .to_str()
-                            .unwrap_or("")
+    fn handle(&mut self, req: Request) -> Response {
+        match req {
+            Request::Hello { .. } => Response::Error {
+                kind: ErrorKind::BadRequest,
+                message: "already connected".to_string(),
+            },
+            Request::Reload => self.reload().unwrap_or_else(|e| e),
+            Request::ConfigPath => Response::ConfigPath {
+                path: self.config.config_file_path.to_string_lossy().into_owned(),
+            },
+            Request::CheckConfig => match fs::read_to_string(&self.config.config_file_path) {
+                Ok(text) => Response::ConfigCheck {
+                    path: self.config.config_file_path.to_string_lossy().into_owned(),
+                    problems: cbak_config::validate(&text, &self.config.config_file_path),
+                },
+                Err(e) => Response::Error {
+                    kind: ErrorKind::Internal,
+                    message: format!("could not read config: {}", e),
+                },
+            },
+            Request::Errors => Response::Errors {
+                watches: state::snapshot()
+                    .into_iter()
+                    .map(|(name, s)| WatchError {
+                        name,
+                        time: s
+                            .last_error
+                            .as_ref()
+                            .map(|(t, _)| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+                        error: s.last_error.map(|(_, e)| e),
                     })
-                    .collect::<Vec<&str>>(),
-            )
-            .current_dir(&config.directory)
-            .output()
-            .unwrap();
-        match Command::new("git")
-            .arg("rm")
-            .arg("-f")
-            .arg("--cached")
-            .args(
-                get_all_files_nfiltered(Path::new(&config.directory), &config.ignore)
-                    .unwrap()
-                    .subdirs
+                    .collect(),
+            },
+            Request::Status => {
+                let states = state::snapshot();
+                let time = |t: &chrono::DateTime<chrono::Local>| t.to_rfc3339();
+                Response::Status {
+                    watches: self
+                        .config
+                        .watch
+                        .iter()
+                        .map(|w| {
+                            let s = states.get(&w.name).cloned().unwrap_or_default();
+                            WatchStatus {
+                                name: w.name.clone(),
+                                directory: w.directory.clone(),
+                                state: s.state,
+                                paused_until: s.paused_until.as_ref().map(time),
+                                last_change: s.last_change.as_ref().map(time),
+                                last_commit: s
+                                    .last_commit
+                                    .map(|(t, id)| CommitRef { id, time: time(&t) }),
+                                pending: s.pending,
+                                last_error_time: s.last_error.as_ref().map(|(t, _)| time(t)),
+                                last_error: s.last_error.map(|(_, e)| e),
+                                restarts: s.restarts,
+                                next_restart: s.restart_at.as_ref().map(time),
+                            }
+                        })
+                        .collect(),
+                }
+            }
+            Request::ListWatches => Response::Watches {
+                watches: self
+                    .config
+                    .watch
                     .iter()
-                    .map(|i| {
-                        i.strip_prefix(&config.directory)
-                            .unwrap()
-                            .to_str()
-                            .unwrap_or("")
+                    .map(|w| WatchInfo {
+                        name: w.name.clone(),
+                        directory: w.directory.clone(),
                     })
-                    .collect::<Vec<&str>>(),
-            )
-            .current_dir(&config.directory)
-            .output()
-        {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Could not run git rm. Do you have git installed?");
-                debug!("{}", e);
-                return;
+                    .collect(),
+            },
+            Request::AddWatch { watch } => self.edit(true, |p| edit::add_watch(p, watch)),
+            Request::RemoveWatch { name } => self.edit(true, |p| edit::remove_watch(p, &name)),
+            Request::GetKey { scope, key } => {
+                match edit::get_key(&self.config.config_file_path, &scope, key.as_deref()) {
+                    Ok(value) => Response::Value { value },
+                    Err(e) => Response::Error {
+                        kind: e.kind(),
+                        message: e.to_string(),
+                    },
+                }
             }
-        };
+            Request::SetKey {
+                scope,
+                key,
+                value,
+                reload,
+            } => self.edit(reload, |p| edit::set_key(p, &scope, &key, value)),
+            Request::Log {
+                name,
+                path,
+                since,
+                until,
+                limit,
+            } => self
+                .log(&name, path, since, until, limit)
+                .unwrap_or_else(|e| e),
+            Request::Diff {
+                name,
+                from,
+                to,
+                path,
+            } => self.diff(&name, from, to, path).unwrap_or_else(|e| e),
+            Request::Restore {
+                name,
+                rev,
+                paths,
+                dry_run,
+            } => self
+                .restore(&name, rev, paths, dry_run)
+                .unwrap_or_else(|e| e),
+            Request::Pause { name, seconds } => self.pause(&name, seconds).unwrap_or_else(|e| e),
+            Request::Resume { name, commit } => self.resume(&name, commit).unwrap_or_else(|e| e),
+            Request::Commit {
+                name,
+                message,
+                author,
+            } => self.commit(&name, message, author).unwrap_or_else(|e| e),
+            Request::Tag { name, tag, message } => {
+                self.tag(&name, tag, message).unwrap_or_else(|e| e)
+            }
+            Request::Tags { name } => self.tags(&name).unwrap_or_else(|e| e),
+            // answered in serve, the content doesn't fit into a response
+            Request::Show { .. } => Response::Error {
+                kind: ErrorKind::Internal,
+                message: "show has to be streamed".to_string(),
+            },
+        }
+    }
 
-        match Command::new("git")
-            .arg("commit")
-            .args(["-m", "auto commit"])
-            .current_dir(&config.directory)
-            .output()
-        {
-            Ok(x) => x,
+    /// Answers requests on one connection until the client hangs up
+    fn serve(&mut self, stream: LocalSocketStream) -> Result<(), ProtoError> {
+        let mut conn = Connection::new(TimedStream::new(stream)?).with_max_line(MAX_REQUEST_LINE);
+        let trusted = match peer_credentials(&conn.get_mut().stream) {
+            Ok((uid, gid)) => may_edit(&self.config.config_file_path, uid, gid),
             Err(e) => {
-                error!("Could not run git commit. Do you have git installed?");
+                warn!("Could not tell who connected, only allowing read-only requests.");
                 debug!("{}", e);
-                return;
+                false
             }
         };
+        match conn.recv::<Request>()? {
+            Some(Request::Hello { version }) if version == PROTOCOL_VERSION => {
+                conn.send(&Response::Hello {
+                    version: PROTOCOL_VERSION,
+                })?;
+            }
+            Some(Request::Hello { version }) => {
+                return conn.send(&Response::Error {
+                    kind: ErrorKind::VersionMismatch,
+                    message: format!(
+                        "daemon speaks protocol version {}, client {}",
+                        PROTOCOL_VERSION, version
+                    ),
+                });
+            }
+            Some(_) => {
+                return conn.send(&Response::Error {
+                    kind: ErrorKind::BadRequest,
+                    message: "expected hello".to_string(),
+                });
+            }
+            None => return Ok(()),
+        }
 
-        //info!("Responce: {:?}", res);
+        let closing = Instant::now() + CONNECTION_TIMEOUT;
+        loop {
+            // the signal is handled once this returns
+            if STOPPING.load(Ordering::Relaxed) || Instant::now() >= closing {
+                return Ok(());
+            }
+            conn.get_mut().deadline = closing.min(Instant::now() + REQUEST_TIMEOUT);
+            let req = match conn.recv::<Request>() {
+                Ok(Some(r)) => r,
+                Ok(None) => return Ok(()),
+                Err(ProtoError::Json(e)) => {
+                    conn.send(&Response::Error {
+                        kind: ErrorKind::BadRequest,
+                        message: e.to_string(),
+                    })?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            debug!("Request: {:?}", req);
+            let res = match req {
+                req if !trusted && needs_write_access(&req) => Response::Error {
+                    kind: ErrorKind::PermissionDenied,
+                    message: format!(
+                        "only users who may write {} can do this",
+                        self.config.config_file_path.display()
+                    ),
+                },
+                Request::Show { name, rev, path } => match self.show(&name, &rev, &path) {
+                    Ok(content) => {
+                        conn.send(&Response::FileContent {
+                            size: content.len() as u64,
+                        })?;
+                        conn.send_raw(&content)?;
+                        continue;
+                    }
+                    Err(e) => e,
+                },
+                req => self.handle(req),
+            };
+            conn.send(&res)?;
+            if let Response::Error { message, .. } = &res {
+                warn!("Request failed: {}", message);
+            }
+        }
     }
 }
 
-//TODO: ms insted of sec
-///Waits until any files in a DirContents is changed
-fn wait_until_changed<'a, 'b>(
-    dir: &'a DirContents,
-    poll_time: i32,
-    wait_time: i32,
-    rx: &'b Receiver<u8>,
-) -> Result<Option<FileChanges<'a>>, Box<dyn std::error::Error>> {
-    let cache_root_time = (&dir.root, dir.root.metadata()?.modified()?);
-    let cache_subdir_time = dir
-        .subdirs
-        .par_iter()
-        .map(|i| (i, i.metadata().unwrap().modified().unwrap()))
-        .collect::<Vec<(&PathBuf, SystemTime)>>();
-    let cache_contents_time = dir
-        .contents
-        .par_iter()
-        .map(|i| (i, i.metadata().unwrap().modified().unwrap()))
-        .collect::<Vec<(&PathBuf, SystemTime)>>();
-    loop {
-        std::thread::sleep(time::Duration::from_secs(poll_time as u64));
-        match rx.try_recv() {
-            Ok(_) | Err(TryRecvError::Disconnected) => {
-                return Ok(None);
-            }
-            Err(TryRecvError::Empty) => {}
-        }
-
-        let root_time = (&dir.root, dir.root.metadata()?.modified()?);
-        let mut subdir_time = dir
-            .subdirs
-            .par_iter()
-            .map(|i| {
-                (
-                    i,
-                    i.metadata()
-                        .unwrap_or_else(|_| unsafe { std::mem::zeroed() })
-                        .modified()
-                        .unwrap_or_else(|_| unsafe { std::mem::zeroed() }),
-                )
-            })
-            .collect::<Vec<(&PathBuf, SystemTime)>>();
-        let mut contents_time = dir
-            .contents
-            .par_iter()
-            .map(|i| {
-                (
-                    i,
-                    i.metadata()
-                        .unwrap_or_else(|_| unsafe { std::mem::zeroed() })
-                        .modified()
-                        .unwrap_or_else(|_| unsafe { std::mem::zeroed() }),
-                )
-            })
-            .collect::<Vec<(&PathBuf, SystemTime)>>();
-        if cache_root_time != root_time {
-            return Ok(Some(FileChanges::File(vec![root_time.0])));
-        }
-        if cache_subdir_time != subdir_time {
-            subdir_time.retain(|i| cache_subdir_time.binary_search(i).is_err());
-            return Ok(Some(FileChanges::File(
-                subdir_time.par_iter().map(|i| i.0).collect(),
-            )));
-        }
-        if cache_contents_time != contents_time {
-            loop {
-                std::thread::sleep(time::Duration::from_secs(wait_time as u64));
-                if contents_time
-                    == dir
-                        .contents
-                        .par_iter()
-                        .map(|i| {
-                            (
-                                i,
-                                i.metadata()
-                                    .unwrap_or_else(|_| unsafe { std::mem::zeroed() })
-                                    .modified()
-                                    .unwrap_or_else(|_| unsafe { std::mem::zeroed() }),
-                            )
-                        })
-                        .collect::<Vec<(&PathBuf, SystemTime)>>()
-                {
-                    break;
-                } else {
-                    contents_time = dir
-                        .contents
-                        .par_iter()
-                        .map(|i| {
-                            (
-                                i,
-                                i.metadata()
-                                    .unwrap_or_else(|_| unsafe { std::mem::zeroed() })
-                                    .modified()
-                                    .unwrap_or_else(|_| unsafe { std::mem::zeroed() }),
-                            )
-                        })
-                        .collect::<Vec<(&PathBuf, SystemTime)>>();
+//TODO: More logging?
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-c --config <PATH> "Config file to use, instead of $CBAKD_CONFIG or the default locations")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-s --socket <NAME> "Socket to listen on, a path or @name for an abstract socket")
+                .required(false),
+        )
+        .arg(
+            arg!(--"log-level" <LEVEL> "Least severe messages to log")
+                .required(false)
+                .value_parser(["error", "warn", "info", "debug", "trace"])
+                .default_value("info"),
+        )
+        .arg(
+            arg!(-v --verbose... "Log more, -v for debug & -vv for trace. Overrides --log-level")
+                .required(false),
+        )
+        .arg(
+            arg!(-f --foreground "Log for a terminal even when running under systemd").required(false),
+        )
+        .arg(arg!(--"check-config" "Check the config file & exit").required(false))
+        .arg(
+            arg!(--once "Commit every watch one time & exit, without watching for changes")
+                .required(false)
+                .conflicts_with("check-config"),
+        )
+        .get_matches();
+
+    let level = match matches.get_count("verbose") {
+        0 => matches
+            .get_one::<String>("log-level")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(LevelFilter::Info),
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    match init_logger(level, matches.get_flag("foreground")) {
+        Ok(_) => {}
+        Err(_) => {
+            eprintln!("Failed to init logger.");
+            return;
+        }
+    };
+
+    let path = match cbak_config::CbakConfig::locate(matches.get_one::<PathBuf>("config").cloned())
+    {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Could not find a config file. {}", e);
+            std::process::exit(1);
+        }
+    };
+    // checking a config mustn't write the default one in its place
+    let check = matches.get_flag("check-config");
+    let loaded = if check {
+        cbak_config::CbakConfig::read(&path)
+    } else {
+        cbak_config::CbakConfig::load(&path)
+    };
+    let config = match loaded {
+        Ok(c) => c,
+        Err(e) => {
+            match e.downcast_ref::<cbak_config::InvalidConfig>() {
+                Some(invalid) => {
+                    error!("Config {} is invalid.", path.display());
+                    for p in &invalid.problems {
+                        error!("{}", p);
+                    }
                 }
+                None => error!("Could not load config {}. {}", path.display(), e),
             }
+            std::process::exit(1);
+        }
+    };
+    if check {
+        for w in &config.warnings {
+            println!("{}", w);
+        }
+        println!(
+            "{} is valid, {} watches",
+            config.config_file_path.display(),
+            config.watch.len()
+        );
+        return;
+    }
+
+    info!("Using config {}.", config.config_file_path.display());
+    for w in &config.warnings {
+        warn!("{}", w.in_file(&config.config_file_path.to_string_lossy()));
+    }
+
+    // Listen for cli updates, connections are handed to the main thread.
+    // --once binds too, so it doesn't commit next to a daemon's workers & no daemon starts meanwhile
+    let name = matches
+        .get_one::<String>("socket")
+        .map(String::as_str)
+        .unwrap_or(cbak_proto::socket_name());
+    let listener = match LocalSocketListener::bind(name) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            error!(
+                "Error: Could not bind to socket {}. Please check if socket is in use.",
+                name
+            );
+            debug!("{}", err);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!("Socket Error.");
+            debug!("{}", err);
+            std::process::exit(1);
+        }
+        Ok(x) => x,
+    };
+
+    info!("Bound to socket {}.", name);
+    // a socket in the filesystem would keep the next start from binding
+    let unbind = || {
+        if name.starts_with('/') {
+            let _ = std::fs::remove_file(name);
+        }
+    };
 
-            contents_time.retain(|i| cache_contents_time.binary_search(i).is_err());
-            return Ok(Some(FileChanges::Modify(
-                contents_time.par_iter().map(|i| i.0).collect(),
-            )));
+    let (tx, events) = mpsc::channel::<Event>();
+    if matches.get_flag("once") {
+        let ok = once(&config);
+        unbind();
+        if !ok {
+            std::process::exit(1);
         }
+        return;
     }
-}
 
-/// Gets all the files in a directory, within a DirContents struct, filtered by the ignore param
-fn get_all_files_filtered(dir: &Path, ignore: &Vec<Regex>) -> std::io::Result<DirContents> {
-    let r = dir.read_dir()?;
-    let mut paths = Vec::new();
-    let mut subdirs = Vec::new();
-    for file in r {
-        match file {
-            Ok(f) => {
-                match f.path().is_dir() {
-                    true => {
-                        let mut r = get_all_files_filtered(&f.path(), ignore)?;
-
-                        // Add subdirs to list
-                        subdirs.push(r.root);
-                        subdirs.append(&mut r.subdirs);
-
-                        paths.append(&mut r.contents);
+    let mut daemon = Daemon {
+        config,
+        workers: Supervisor::new(tx.clone()),
+    };
+
+    // only one daemon gets this far for a socket & its workers aren't running yet,
+    // an old lock is left over from a run that got killed
+    for w in &daemon.config.watch {
+        vcs::remove_stale_lock(w);
+    }
+    daemon.spawn_workers();
+
+    match Signals::new([SIGTERM, SIGINT, SIGHUP]) {
+        Ok(mut signals) => {
+            let tx = tx.clone();
+            let spawned = std::thread::Builder::new()
+                .name("signals".to_string())
+                .spawn(move || {
+                    for sig in signals.forever() {
+                        if sig != SIGHUP {
+                            STOPPING.store(true, Ordering::Relaxed);
+                        }
+                        if tx.send(Event::Signal(sig)).is_err() {
+                            break;
+                        }
                     }
-                    false => paths.push(f.path()),
+                });
+            if let Err(e) = spawned {
+                warn!("Could not start signal handler thread.");
+                debug!("{}", e);
+            }
+        }
+        Err(e) => {
+            warn!("Could not install signal handlers.");
+            debug!("{}", e);
+        }
+    }
+
+    let spawned = std::thread::Builder::new()
+        .name("listener".to_string())
+        .spawn(move || {
+            for conn in listener.incoming().filter_map(handle_socket_error) {
+                if tx.send(Event::Connection(conn)).is_err() {
+                    break;
                 }
             }
-            Err(_) => continue,
-        }
-    }
-    // Remvoe duplicate subdirs
-    subdirs.sort();
-    subdirs.dedup();
-
-    Ok(DirContents {
-        root: dir.to_path_buf(),
-        subdirs: subdirs
-            .par_iter()
-            .filter(|p| !matches(p.to_str().unwrap(), ignore))
-            .map(|p| p.to_owned())
-            .collect::<Vec<PathBuf>>(),
-        contents: paths
-            .par_iter()
-            .filter(|p| !matches(p.to_str().unwrap(), ignore))
-            .map(|p| p.to_owned())
-            .collect::<Vec<PathBuf>>(),
-    })
+        });
+    if let Err(e) = spawned {
+        error!("Could not start socket listener thread.");
+        debug!("{}", e);
+        return;
+    }
+
+    // connections are served one at a time, in between signals, worker exits & restarts
+    loop {
+        let event = match daemon.workers.next_restart() {
+            Some(at) => match events.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                Ok(e) => e,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    daemon.workers.restart_due();
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match events.recv() {
+                Ok(e) => e,
+                Err(_) => break,
+            },
+        };
+        match event {
+            Event::Connection(conn) => {
+                if let Err(e) = daemon.serve(conn) {
+                    warn!("Connection closed. {}", e);
+                }
+            }
+            Event::Signal(SIGHUP) => {
+                info!("SIGHUP received, reloading config.");
+                // a failed reload is logged, there is nobody else to tell
+                let _ = daemon.reload();
+            }
+            Event::Signal(sig) => {
+                info!("Signal {} received, shutting down.", sig);
+                break;
+            }
+            Event::WorkerExited(exit) => daemon.workers.exited(exit),
+        }
+    }
+
+    daemon.workers.shutdown(&events, SHUTDOWN_TIMEOUT);
+    unbind();
+    info!("Stopped.");
 }
 
-/// Gets all the files in a directory, within a DirContents struct, that would of been removed by the ignore param
-fn get_all_files_nfiltered(dir: &Path, ignore: &Vec<Regex>) -> std::io::Result<DirContents> {
-    let r = dir.read_dir()?;
-    let mut paths = Vec::new();
-    let mut subdirs = Vec::new();
-    for file in r {
-        match file {
-            Ok(f) => {
-                match f.path().is_dir() {
-                    true => {
-                        let mut r = get_all_files_filtered(&f.path(), ignore)?;
-                        // Add subdirs to list
-                        subdirs.push(r.root);
-                        subdirs.append(&mut r.subdirs);
-
-                        paths.append(&mut r.contents);
+/// Commits whatever changed in every watch, for `--once`. Returns whether all of them succeeded.
+fn once(config: &cbak_config::CbakConfig) -> bool {
+    let mut ok = true;
+    for w in &config.watch {
+        let changes = match history::uncommitted(w) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("Could not list changes in {}. {}", w.directory, e);
+                vec![]
+            }
+        };
+        let res = vcs::open(w)
+            .and_then(|mut repo| repo.commit_all(&message::render(&w.commit_message, w, &changes)));
+        match res {
+            Ok(Some(id)) => info!("Committed {} in {}", id, w.name),
+            Ok(None) => info!("Nothing to commit in {}", w.name),
+            Err(e) => {
+                error!("Could not commit changes in {}. {}", w.directory, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Watches one directory until told to stop, `Err` if the worker can't go on.
+/// The supervisor starts it again after a while then.
+fn run(mut config: cbak_config::DirConfig, rx: Receiver<WorkerMsg>) -> Result<(), String> {
+    let mut repo = match vcs::open(&config) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("could not open repository: {}", e)),
+    };
+    if let Some((time, id)) = history::head(&config) {
+        state::committed(&config.name, time, id);
+    }
+    let mut watcher = watcher::new_watcher(&config);
+
+    // set while paused, with the time a timed pause ends
+    let mut paused: Option<Option<Instant>> = None;
+    // changes that haven't been committed yet, because the worker is paused or the last commit failed
+    let mut pending: Vec<FileChanges> = vec![];
+
+    // main watch loop
+    loop {
+        match watcher.wait(&rx) {
+            Ok(Wake::Changes(c)) => {
+                pending = watcher::coalesce(std::mem::take(&mut pending).into_iter().chain(c));
+                state::changes_detected(&config.name, pending.len());
+                if paused.is_some() {
+                    continue;
+                }
+            }
+            Ok(Wake::Message(WorkerMsg::Stop)) => {
+                warn!(
+                    "Terminating thread {}",
+                    std::thread::current().name().unwrap_or("")
+                );
+                return Ok(());
+            }
+            Ok(Wake::Message(WorkerMsg::Reconfigure(c))) => {
+                debug!("New config for {}", c.name);
+                config = c;
+                continue;
+            }
+            Ok(Wake::Message(WorkerMsg::Restore { rev, paths, reply })) => {
+                let res = restore::restore(&config, repo.as_mut(), watcher.as_mut(), &rev, &paths)
+                    .map_err(|e| {
+                        error!("Could not restore {} in {}. {}", rev, config.directory, e);
+                        e.to_string()
+                    });
+                if let Ok(r) = &res {
+                    // restoring commits whatever was pending first
+                    pending.clear();
+                    match &r.commit {
+                        Some(id) => {
+                            state::committed(&config.name, chrono::Local::now(), id.clone())
+                        }
+                        None => state::up_to_date(&config.name),
                     }
-                    false => paths.push(f.path()),
                 }
+                // the client may have given up waiting
+                let _ = reply.send(res);
+                continue;
             }
-            Err(_) => continue,
-        }
-    }
-    // Remvoe duplicate subdirs
-    subdirs.sort();
-    subdirs.dedup();
-
-    Ok(DirContents {
-        root: dir.to_path_buf(),
-        subdirs: subdirs
-            .par_iter()
-            .filter(|p| matches(p.to_str().unwrap(), ignore))
-            .map(|p| p.to_owned())
-            .collect::<Vec<PathBuf>>(),
-        contents: paths
-            .par_iter()
-            .filter(|p| matches(p.to_str().unwrap(), ignore))
-            .map(|p| p.to_owned())
-            .collect::<Vec<PathBuf>>(),
-    })
-}
+            Ok(Wake::Message(WorkerMsg::Commit {
+                message,
+                author,
+                reply,
+            })) => {
+                // a manual commit takes everything, even while paused
+                let res = repo.commit_as(&message, author.as_ref()).map_err(|e| {
+                    error!("Could not commit changes in {}. {}", config.directory, e);
+                    e.to_string()
+                });
+                match &res {
+                    Ok(Some(id)) => {
+                        info!("Committed {} in {} on request", id, config.name);
+                        state::committed(&config.name, chrono::Local::now(), id.clone());
+                        pending.clear();
+                    }
+                    Ok(None) => {
+                        state::up_to_date(&config.name);
+                        pending.clear();
+                    }
+                    Err(e) => state::set_error(&config.name, format!("commit failed: {}", e)),
+                }
+                let _ = reply.send(res);
+                continue;
+            }
+            Ok(Wake::Message(WorkerMsg::Tag {
+                name,
+                message,
+                reply,
+            })) => {
+                // the tag is meant for the state on disk, so whatever is pending goes in first
+                pending = history::against_head(&config, std::mem::take(&mut pending));
+                let pre = message::render(&config.commit_message, &config, &pending);
+                let res = match repo.commit_all(&pre) {
+                    Ok(commit) => {
+                        match commit {
+                            Some(id) => state::committed(&config.name, chrono::Local::now(), id),
+                            None => state::up_to_date(&config.name),
+                        }
+                        pending.clear();
+                        repo.tag(&name, &message)
+                    }
+                    Err(e) => Err(e),
+                }
+                .map_err(|e| {
+                    error!("Could not tag {} in {}. {}", name, config.directory, e);
+                    e.to_string()
+                });
+                if res.is_ok() {
+                    info!("Tagged {} in {}", name, config.name);
+                }
+                let _ = reply.send(res);
+                continue;
+            }
+            Ok(Wake::Message(WorkerMsg::Pause { duration })) => {
+                info!("Pausing {}", config.name);
+                paused = Some(duration.map(|d| Instant::now() + d));
+                continue;
+            }
+            Ok(Wake::Message(WorkerMsg::PauseExpired)) => match paused {
+                Some(Some(until)) if until <= Instant::now() => {
+                    info!("Pause of {} is over", config.name);
+                    paused = None;
+                    state::resumed(&config.name);
+                }
+                _ => continue,
+            },
+            Ok(Wake::Message(WorkerMsg::Resume { commit })) => {
+                if paused.take().is_none() {
+                    continue;
+                }
+                info!("Resuming {}", config.name);
+                if !commit {
+                    continue;
+                }
+            }
+            Err(e) => {
+                error!("Could not check {} for changes. {}", config.directory, e);
+                state::set_error(&config.name, format!("could not check for changes: {}", e));
+                if paused.is_some() {
+                    continue;
+                }
+            }
+        };
+
+        pending = history::against_head(&config, std::mem::take(&mut pending));
+        let message = message::render(&config.commit_message, &config, &pending);
+        debug!("Committing {}: {}", config.name, message);
 
-fn matches(input: &str, pattern: &[Regex]) -> bool {
-    pattern
-        .iter()
-        .map(|f| f.is_match(input).unwrap_or(true))
-        .any(|x| !x)
+        match repo.commit_all(&message) {
+            Ok(Some(id)) => {
+                info!("Committed {} in {}", id, config.name);
+                state::committed(&config.name, chrono::Local::now(), id);
+                pending.clear();
+            }
+            Ok(None) => {
+                state::up_to_date(&config.name);
+                pending.clear();
+            }
+            Err(e) => {
+                error!("Could not commit changes in {}. {}", config.directory, e);
+                state::set_error(&config.name, format!("commit failed: {}", e));
+            }
+        };
+    }
 }
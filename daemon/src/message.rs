@@ -0,0 +1,212 @@
+use std::path::Path;
+
+use cbak_config::DirConfig;
+
+use crate::watcher::FileChanges;
+
+/// Most changes `{files}` lists, the rest are counted on one more line
+const MAX_FILES: usize = 50;
+
+/// Fills in a commit message template for a batch of changes.
+///
+/// Supported placeholders:
+/// - `{hostname}`  name of this machine
+/// - `{name}`      name of the watch entry
+/// - `{directory}` watched directory
+/// - `{count}`     number of changed files
+/// - `{timestamp}` local time of the commit
+/// - `{summary}`   one line description of the changes
+/// - `{files}`     one line per change, e.g. `modified: nginx.conf`, up to `MAX_FILES` of them
+///
+/// Unknown placeholders are left as they are.
+pub fn render(template: &str, config: &DirConfig, changes: &[FileChanges]) -> String {
+    let hostname = hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "localhost".to_string());
+
+    template
+        .replace("{hostname}", &hostname)
+        .replace("{name}", &config.name)
+        .replace("{directory}", &config.directory)
+        .replace("{count}", &changes.len().to_string())
+        .replace(
+            "{timestamp}",
+            &chrono::Local::now()
+                .format("%Y-%m-%d %H:%M:%S %z")
+                .to_string(),
+        )
+        .replace("{summary}", &summary(config, changes))
+        .replace("{files}", &files(config, changes))
+        .trim_end()
+        .to_string()
+}
+
+/// Message of the commit recording a `cbak restore`
+pub fn restore(config: &DirConfig, rev: &str, from: &str, changes: &[FileChanges]) -> String {
+    let what = match changes {
+        [c] => relative(config, c.path()),
+        _ => format!("{} files", changes.len()),
+    };
+    render(
+        &format!(
+            "[{{hostname}}] {{name}}: restore {} from {} ({})\n\n{{files}}",
+            what,
+            &from[..8],
+            rev
+        ),
+        config,
+        changes,
+    )
+}
+
+/// Short description of a batch, names the file if only one changed
+fn summary(config: &DirConfig, changes: &[FileChanges]) -> String {
+    match changes {
+        [] => "snapshot".to_string(),
+        [c] => describe(config, c),
+        _ => {
+            let count = |f: fn(&FileChanges) -> bool| changes.iter().filter(|c| f(c)).count();
+            let parts = [
+                (count(|c| matches!(c, FileChanges::Modified(_))), "modified"),
+                (count(|c| matches!(c, FileChanges::Created(_))), "created"),
+                (count(|c| matches!(c, FileChanges::Deleted(_))), "deleted"),
+                (count(|c| matches!(c, FileChanges::Renamed(..))), "renamed"),
+            ]
+            .iter()
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, kind)| format!("{} {}", n, kind))
+            .collect::<Vec<String>>();
+            format!("{} files changed ({})", changes.len(), parts.join(", "))
+        }
+    }
+}
+
+fn files(config: &DirConfig, changes: &[FileChanges]) -> String {
+    let mut lines = changes
+        .iter()
+        .take(MAX_FILES)
+        .map(|c| describe(config, c))
+        .collect::<Vec<String>>();
+    if changes.len() > MAX_FILES {
+        lines.push(format!("... & {} more", changes.len() - MAX_FILES));
+    }
+    lines.join("\n")
+}
+
+fn describe(config: &DirConfig, change: &FileChanges) -> String {
+    match change {
+        FileChanges::Created(p) => format!("created: {}", relative(config, p)),
+        FileChanges::Deleted(p) => format!("deleted: {}", relative(config, p)),
+        FileChanges::Modified(p) => format!("modified: {}", relative(config, p)),
+        FileChanges::Renamed(from, to) => format!(
+            "renamed: {} -> {}",
+            relative(config, from),
+            relative(config, to)
+        ),
+    }
+}
+
+/// Path relative to the watched directory, as git shows it
+fn relative(config: &DirConfig, path: &Path) -> String {
+    path.strip_prefix(&config.directory)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use cbak_config::{VcsKind, WatcherKind};
+
+    use super::*;
+
+    fn config() -> DirConfig {
+        DirConfig {
+            name: "nginx".to_string(),
+            directory: "/etc/nginx".to_string(),
+            ignore: vec![],
+            poll_interval: 30,
+            write_delay: 30,
+            watcher: WatcherKind::Inotify,
+            vcs: VcsKind::Libgit2,
+            commit_message: String::new(),
+            git_dir: PathBuf::from("/var/lib/cbakd/nginx.git"),
+        }
+    }
+
+    fn p(s: &str) -> PathBuf {
+        PathBuf::from(s)
+    }
+
+    #[test]
+    fn placeholders() {
+        let changes = [FileChanges::Modified(p("/etc/nginx/nginx.conf"))];
+        assert_eq!(
+            render(
+                "{name} in {directory}: {summary} ({count})\n\n{files}",
+                &config(),
+                &changes
+            ),
+            "nginx in /etc/nginx: modified: nginx.conf (1)\n\nmodified: nginx.conf"
+        );
+        let hostname = hostname::get().unwrap().to_string_lossy().into_owned();
+        assert_eq!(
+            render("[{hostname}]", &config(), &changes),
+            format!("[{}]", hostname)
+        );
+        let stamp = render("{timestamp}", &config(), &changes);
+        assert!(chrono::DateTime::parse_from_str(&stamp, "%Y-%m-%d %H:%M:%S %z").is_ok());
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        assert_eq!(
+            render("{name}: {nope} {Name} {", &config(), &[]),
+            "nginx: {nope} {Name} {"
+        );
+    }
+
+    #[test]
+    fn summaries() {
+        assert_eq!(render("{summary}", &config(), &[]), "snapshot");
+        let changes = [
+            FileChanges::Modified(p("/etc/nginx/nginx.conf")),
+            FileChanges::Created(p("/etc/nginx/sites/a")),
+            FileChanges::Created(p("/etc/nginx/sites/b")),
+            FileChanges::Renamed(p("/etc/nginx/old"), p("/etc/nginx/new")),
+        ];
+        assert_eq!(
+            render("{summary}\n\n{files}", &config(), &changes),
+            "4 files changed (1 modified, 2 created, 1 renamed)
+
+modified: nginx.conf
+created: sites/a
+created: sites/b
+renamed: old -> new"
+        );
+    }
+
+    #[test]
+    fn long_file_lists_are_cut() {
+        let changes = (0..MAX_FILES + 10)
+            .map(|i| FileChanges::Created(p(&format!("/etc/nginx/{}", i))))
+            .collect::<Vec<FileChanges>>();
+        let message = render("{summary}\n\n{files}", &config(), &changes);
+        let lines = message.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "60 files changed (60 created)");
+        assert_eq!(lines.len(), 2 + MAX_FILES + 1);
+        assert_eq!(lines[2], "created: 0");
+        assert_eq!(lines[MAX_FILES + 1], "created: 49");
+        assert_eq!(lines[MAX_FILES + 2], "... & 10 more");
+    }
+
+    #[test]
+    fn restore_message() {
+        let changes = [FileChanges::Modified(p("/etc/nginx/nginx.conf"))];
+        let message = restore(&config(), "HEAD~1", "0123456789abcdef", &changes);
+        assert!(message
+            .ends_with("nginx: restore nginx.conf from 01234567 (HEAD~1)\n\nmodified: nginx.conf"));
+    }
+}
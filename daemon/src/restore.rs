@@ -0,0 +1,210 @@
+//! Putting files of a watch back the way they were at an earlier commit.
+
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{fchown, lchown, symlink, MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::Path,
+};
+
+use cbak_config::DirConfig;
+use cbak_proto::{ChangeKind, ChangedFile};
+use git2::{Delta, DiffOptions, Repository, Tree};
+use log::info;
+
+use crate::{
+    history, message,
+    vcs::Vcs,
+    watcher::{matches, FileChanges, Watcher},
+};
+
+/// Outcome of a restore
+#[derive(Debug)]
+pub struct Restored {
+    /// Commit the files were taken from
+    pub from: String,
+    /// What happens to the files on disk, `Added` files are brought back & `Deleted` ones removed
+    pub files: Vec<ChangedFile>,
+    /// Commit recording the restore, `None` for a dry run or if nothing had to change
+    pub commit: Option<String>,
+}
+
+fn source<'r>(repo: &'r Repository, rev: &str) -> Result<(String, Tree<'r>), Box<dyn Error>> {
+    let commit = history::resolve(repo, rev)?
+        .ok_or_else(|| format!("nothing was committed before {}", rev))?;
+    Ok((commit.id().to_string(), commit.tree()?))
+}
+
+/// Works out what restoring `paths` (everything if empty) to `rev` would change, without touching anything
+pub fn plan(config: &DirConfig, rev: &str, paths: &[String]) -> Result<Restored, Box<dyn Error>> {
+    let repo = history::open(config)?;
+    let (from, tree) = source(&repo, rev)?;
+
+    let mut opts = DiffOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    paths.iter().for_each(|p| {
+        opts.pathspec(p);
+    });
+    let diff = repo.diff_tree_to_workdir(Some(&tree), Some(&mut opts))?;
+
+    let root = Path::new(&config.directory);
+    let files = diff
+        .deltas()
+        .filter_map(|d| {
+            // the diff goes from the old tree to the disk, a restore goes the other way
+            let change = match d.status() {
+                Delta::Added | Delta::Untracked => ChangeKind::Deleted,
+                Delta::Deleted => ChangeKind::Added,
+                Delta::Modified | Delta::Typechange => ChangeKind::Modified,
+                _ => return None,
+            };
+            let path = d.new_file().path().or_else(|| d.old_file().path())?;
+            if matches(root.join(path).to_str().unwrap_or(""), &config.ignore) {
+                return None;
+            }
+            Some(ChangedFile {
+                change,
+                path: path.to_string_lossy().into_owned(),
+                old_path: None,
+            })
+        })
+        .collect();
+
+    Ok(Restored {
+        from,
+        files,
+        commit: None,
+    })
+}
+
+/// Restores the files & commits the result. Runs on the worker so nothing gets committed halfway through.
+pub fn restore(
+    config: &DirConfig,
+    vcs: &mut dyn Vcs,
+    watcher: &mut dyn Watcher,
+    rev: &str,
+    paths: &[String],
+) -> Result<Restored, Box<dyn Error>> {
+    // keep whatever hasn't been committed yet, so the restore can be undone
+    vcs.commit_all(&message::render(&config.commit_message, config, &[]))?;
+
+    let mut restored = plan(config, rev, paths)?;
+    if restored.files.is_empty() {
+        return Ok(restored);
+    }
+
+    let repo = history::open(config)?;
+    let (_, tree) = source(&repo, &restored.from)?;
+    let root = Path::new(&config.directory);
+    for f in &restored.files {
+        let dest = root.join(&f.path);
+        match f.change {
+            ChangeKind::Deleted => match fs::remove_file(&dest) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            _ => write_entry(&repo, &tree, Path::new(&f.path), &dest)?,
+        }
+    }
+    // our own writes aren't changes to report
+    watcher.reset()?;
+
+    let changes = restored
+        .files
+        .iter()
+        .map(|f| {
+            let p = root.join(&f.path);
+            match f.change {
+                ChangeKind::Added => FileChanges::Created(p),
+                ChangeKind::Deleted => FileChanges::Deleted(p),
+                _ => FileChanges::Modified(p),
+            }
+        })
+        .collect::<Vec<FileChanges>>();
+    restored.commit = vcs.commit_all(&message::restore(config, rev, &restored.from, &changes))?;
+    info!(
+        "Restored {} files in {} from {}",
+        restored.files.len(),
+        config.name,
+        restored.from
+    );
+    Ok(restored)
+}
+
+/// Writes a file from a tree to disk, replacing whatever is there in one rename.
+/// A file that is replaced keeps its mode & owner, git only knows whether a file is executable.
+/// Files that don't exist anymore come back as 0644 or 0755, owned by cbakd
+fn write_entry(
+    repo: &Repository,
+    tree: &Tree,
+    path: &Path,
+    dest: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let entry = tree.get_path(path)?;
+    let blob = entry.to_object(repo)?.peel_to_blob()?;
+    let dir = dest.parent().ok_or("invalid path")?;
+    fs::create_dir_all(dir)?;
+    // doesn't follow a symlink, its own owner is what gets kept
+    let existing = fs::symlink_metadata(dest).ok();
+
+    let tmp = dir.join(format!(
+        ".{}.cbak-restore",
+        dest.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    // a leftover from an earlier restore, or something planted there to be written through
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let written = if entry.filemode() == 0o120000 {
+        write_symlink(&tmp, blob.content(), existing.as_ref())
+    } else {
+        let mode = match &existing {
+            Some(m) if m.is_file() => m.mode() & 0o7777,
+            _ if entry.filemode() == 0o100755 => 0o755,
+            _ => 0o644,
+        };
+        write_file(&tmp, blob.content(), mode, existing.as_ref())
+    };
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp, dest)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Creates `tmp` exclusively, so nothing planted at its path gets written through.
+/// `owner` is the file `tmp` is about to replace, it gets that file's uid & gid
+pub(crate) fn write_file(
+    tmp: &Path,
+    content: &[u8],
+    mode: u32,
+    owner: Option<&fs::Metadata>,
+) -> io::Result<()> {
+    // only readable by cbakd until the mode is set
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(tmp)?;
+    file.write_all(content)?;
+    if let Some(m) = owner {
+        fchown(&file, Some(m.uid()), Some(m.gid()))?;
+    }
+    // after chown, which clears setuid & setgid
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.sync_all()
+}
+
+fn write_symlink(tmp: &Path, target: &[u8], owner: Option<&fs::Metadata>) -> io::Result<()> {
+    symlink(OsStr::from_bytes(target), tmp)?;
+    match owner {
+        Some(m) => lchown(tmp, Some(m.uid()), Some(m.gid())),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use cbak_proto::WorkerState;
+use chrono::{DateTime, Local};
+
+/// Runtime information about a worker, shared with the socket handler
+#[derive(Clone, Debug)]
+pub struct WatchState {
+    pub state: WorkerState,
+    /// When a timed pause ends
+    pub paused_until: Option<DateTime<Local>>,
+    /// When the watcher last reported changes
+    pub last_change: Option<DateTime<Local>>,
+    /// When the last commit was made & its id
+    pub last_commit: Option<(DateTime<Local>, String)>,
+    /// Changes detected since the last successful commit
+    pub pending: usize,
+    /// When the last failure happened & what it was, cleared by the next successful commit
+    pub last_error: Option<(DateTime<Local>, String)>,
+    /// Times the worker was restarted after failing, since it last ran long enough to count as healthy
+    pub restarts: u32,
+    /// When a failed worker is started again
+    pub restart_at: Option<DateTime<Local>>,
+}
+
+impl Default for WatchState {
+    fn default() -> Self {
+        Self {
+            state: WorkerState::Running,
+            paused_until: None,
+            last_change: None,
+            last_commit: None,
+            pending: 0,
+            last_error: None,
+            restarts: 0,
+            restart_at: None,
+        }
+    }
+}
+
+static WATCH_STATE: Mutex<BTreeMap<String, WatchState>> = Mutex::new(BTreeMap::new());
+
+fn update(name: &str, f: impl FnOnce(&mut WatchState)) {
+    // a worker panicking while holding the lock shouldn't take the state down with it
+    let mut state = WATCH_STATE.lock().unwrap_or_else(|e| e.into_inner());
+    f(state.entry(name.to_string()).or_default());
+}
+
+pub fn register(name: &str) {
+    update(name, |_| {});
+}
+
+/// A worker thread was started, any earlier pause ended with the previous thread
+pub fn started(name: &str) {
+    update(name, |s| {
+        s.state = WorkerState::Running;
+        s.paused_until = None;
+        s.restart_at = None;
+    });
+}
+
+pub fn restarting(name: &str, restarts: u32, at: DateTime<Local>) {
+    update(name, |s| {
+        s.state = WorkerState::Failed;
+        s.restarts = restarts;
+        s.restart_at = Some(at);
+    });
+}
+
+pub fn paused(name: &str, until: Option<DateTime<Local>>) {
+    update(name, |s| {
+        s.state = WorkerState::Paused;
+        s.paused_until = until;
+    });
+}
+
+pub fn resumed(name: &str) {
+    update(name, |s| {
+        s.state = WorkerState::Running;
+        s.paused_until = None;
+    });
+}
+
+pub fn set_error(name: &str, error: String) {
+    update(name, |s| s.last_error = Some((Local::now(), error)));
+}
+
+/// `pending` is the number of changes waiting for a commit, including the new ones
+pub fn changes_detected(name: &str, pending: usize) {
+    update(name, |s| {
+        s.last_change = Some(Local::now());
+        s.pending = pending;
+    });
+}
+
+/// Records a successful commit, which also means nothing is pending & nothing is failing anymore
+pub fn committed(name: &str, time: DateTime<Local>, id: String) {
+    update(name, |s| {
+        s.last_commit = Some((time, id));
+        s.pending = 0;
+        s.last_error = None;
+    });
+}
+
+/// The worker looked & there was nothing to commit
+pub fn up_to_date(name: &str) {
+    update(name, |s| {
+        s.pending = 0;
+        s.last_error = None;
+    });
+}
+
+/// Forgets a watch, used when its worker is stopped
+pub fn remove(name: &str) {
+    WATCH_STATE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(name);
+}
+
+pub fn snapshot() -> BTreeMap<String, WatchState> {
+    WATCH_STATE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
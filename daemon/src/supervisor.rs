@@ -0,0 +1,344 @@
+//! Owns the worker threads, notices when one fails or panics & starts it again after a while.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use cbak_config::DirConfig;
+use log::{debug, error, info, warn};
+
+use crate::{state, watcher::WorkerMsg, Event};
+
+/// Wait before the first restart of a failed worker, doubled for every restart after it
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait between restarts. A worker that ran this long before failing starts over at `RESTART_DELAY`
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// How long a reload waits for the workers it replaces, the daemon doesn't answer anything meanwhile
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often `stop` looks whether the workers are done
+const STOP_POLL: Duration = Duration::from_millis(50);
+
+/// How a worker thread ended
+#[derive(Debug)]
+pub struct Exit {
+    pub name: String,
+    /// Tells a worker apart from earlier ones of the same watch
+    pub id: u64,
+    /// `Err` if the worker gave up or panicked
+    pub result: Result<(), String>,
+}
+
+struct Thread {
+    id: u64,
+    handle: JoinHandle<()>,
+    tx: Sender<WorkerMsg>,
+    started: Instant,
+}
+
+struct Worker {
+    config: DirConfig,
+    /// `None` while waiting for a restart
+    thread: Option<Thread>,
+    /// Restarts since the worker last ran long enough to count as healthy
+    restarts: u32,
+    restart_at: Option<Instant>,
+}
+
+/// The worker threads, by watch name
+pub struct Supervisor {
+    workers: BTreeMap<String, Worker>,
+    /// Stopped workers that didn't finish within `STOP_TIMEOUT`, by watch name.
+    /// Their watch gets no new worker before they return, two of them would commit to the same repository
+    left_behind: BTreeMap<String, Thread>,
+    /// Workers report here when they return
+    events: Sender<Event>,
+    next_id: u64,
+}
+
+impl Supervisor {
+    pub fn new(events: Sender<Event>) -> Self {
+        Self {
+            workers: BTreeMap::new(),
+            left_behind: BTreeMap::new(),
+            events,
+            next_id: 0,
+        }
+    }
+
+    /// Channel of the worker of a watch, `None` if it isn't running
+    pub fn sender(&self, name: &str) -> Option<&Sender<WorkerMsg>> {
+        self.workers.get(name)?.thread.as_ref().map(|t| &t.tx)
+    }
+
+    /// Starts a worker for a watch that doesn't have one yet
+    pub fn start(&mut self, config: DirConfig) {
+        state::register(&config.name);
+        let name = config.name.clone();
+        self.workers.insert(
+            name.clone(),
+            Worker {
+                config,
+                thread: None,
+                restarts: 0,
+                restart_at: None,
+            },
+        );
+        self.launch(&name);
+    }
+
+    fn launch(&mut self, name: &str) {
+        if self.left_behind.contains_key(name) {
+            info!("Waiting for the previous worker of {} to stop.", name);
+            return;
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        let Some(w) = self.workers.get_mut(name) else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel::<WorkerMsg>();
+        let events = self.events.clone();
+        let config = w.config.clone();
+        let spawned = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let name = config.name.clone();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| crate::run(config, rx)))
+                    .unwrap_or_else(|p| Err(format!("worker panicked: {}", panic_message(&p))));
+                let _ = events.send(Event::WorkerExited(Exit { name, id, result }));
+            });
+        match spawned {
+            Ok(handle) => {
+                debug!("Spawned worker thread for {}.", name);
+                state::started(name);
+                w.thread = Some(Thread {
+                    id,
+                    handle,
+                    tx,
+                    started: Instant::now(),
+                });
+            }
+            Err(e) => {
+                error!("Could not start worker for {}.", name);
+                debug!("{}", e);
+                self.failed(name, format!("could not start worker: {}", e));
+            }
+        }
+    }
+
+    /// Schedules the next restart of a worker that isn't running anymore
+    fn failed(&mut self, name: &str, error: String) {
+        let Some(w) = self.workers.get_mut(name) else {
+            return;
+        };
+        let delay = RESTART_DELAY
+            .checked_mul(2u32.saturating_pow(w.restarts))
+            .unwrap_or(MAX_RESTART_DELAY)
+            .min(MAX_RESTART_DELAY);
+        w.restarts += 1;
+        w.restart_at = Some(Instant::now() + delay);
+        warn!(
+            "Restarting worker of {} in {} seconds.",
+            name,
+            delay.as_secs()
+        );
+        state::set_error(name, error);
+        state::restarting(
+            name,
+            w.restarts,
+            chrono::Local::now() + chrono::Duration::seconds(delay.as_secs() as i64),
+        );
+    }
+
+    /// Handles a worker thread returning
+    pub fn exited(&mut self, exit: Exit) {
+        if self.left_behind.get(&exit.name).map(|t| t.id) == Some(exit.id) {
+            if let Some(t) = self.left_behind.remove(&exit.name) {
+                let _ = t.handle.join();
+            }
+            info!("Previous worker of {} stopped.", exit.name);
+            // a watch that is still configured has been waiting for it
+            if self
+                .workers
+                .get(&exit.name)
+                .is_some_and(|w| w.thread.is_none() && w.restart_at.is_none())
+            {
+                self.launch(&exit.name);
+            }
+            return;
+        }
+        let Some(w) = self.workers.get_mut(&exit.name) else {
+            return;
+        };
+        // a worker that was replaced since, e.g. by a reload
+        if w.thread.as_ref().map(|t| t.id) != Some(exit.id) {
+            return;
+        }
+        let Some(t) = w.thread.take() else {
+            return;
+        };
+        let ran = t.started.elapsed();
+        let _ = t.handle.join();
+        match exit.result {
+            Ok(()) => debug!("Worker of {} returned.", exit.name),
+            Err(e) => {
+                error!("Worker of {} failed. {}", exit.name, e);
+                if ran >= MAX_RESTART_DELAY {
+                    w.restarts = 0;
+                }
+                self.failed(&exit.name, e);
+            }
+        }
+    }
+
+    /// When the next restart is due
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.workers.values().filter_map(|w| w.restart_at).min()
+    }
+
+    /// Starts the workers whose restart is due
+    pub fn restart_due(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .workers
+            .iter_mut()
+            .filter(|(_, w)| w.restart_at.is_some_and(|t| t <= now))
+            .map(|(name, w)| {
+                w.restart_at = None;
+                name.clone()
+            })
+            .collect::<Vec<String>>();
+        for name in due {
+            info!("Restarting worker of {}", name);
+            self.launch(&name);
+        }
+    }
+
+    /// Stops workers & gives them `STOP_TIMEOUT` to finish what they're doing.
+    /// One that takes longer is left to finish on its own, see `left_behind`
+    pub fn stop(&mut self, names: &[String]) {
+        let mut running = vec![];
+        for name in names {
+            if let Some(Worker {
+                thread: Some(t), ..
+            }) = self.workers.remove(name)
+            {
+                // fails if the worker is gone already, it counts as finished then
+                let _ = t.tx.send(WorkerMsg::Stop);
+                running.push((name, t));
+            }
+            state::remove(name);
+        }
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while running.iter().any(|(_, t)| !t.handle.is_finished()) && Instant::now() < deadline {
+            std::thread::sleep(STOP_POLL);
+        }
+        for (name, t) in running {
+            if t.handle.is_finished() {
+                let _ = t.handle.join();
+            } else {
+                warn!("Worker of {} didn't stop in time, leaving it behind.", name);
+                self.left_behind.insert(name.clone(), t);
+            }
+        }
+    }
+
+    /// Brings the workers in line with a new list of watches, only touching the ones that changed
+    pub fn apply(&mut self, watches: &[DirConfig]) {
+        let stale = self
+            .workers
+            .iter()
+            .filter(|(name, w)| {
+                // workers that gave up get another try right away
+                if w.thread.is_none() {
+                    return true;
+                }
+                // a new commit message doesn't need a rescan, everything else does
+                match watches.iter().find(|c| &c.name == *name) {
+                    Some(c) => {
+                        DirConfig {
+                            commit_message: w.config.commit_message.clone(),
+                            ..c.clone()
+                        } != w.config
+                    }
+                    None => true,
+                }
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<String>>();
+        for name in &stale {
+            info!("Stopping worker of {}", name);
+        }
+        self.stop(&stale);
+
+        for c in watches {
+            match self.workers.get_mut(&c.name) {
+                Some(w) if w.config != *c => {
+                    info!("Reconfiguring worker of {}", c.name);
+                    w.config = c.clone();
+                    if let Some(t) = &w.thread {
+                        let _ = t.tx.send(WorkerMsg::Reconfigure(c.clone()));
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    info!("Starting worker of {}", c.name);
+                    self.start(c.clone());
+                }
+            }
+        }
+    }
+
+    /// Stops every worker, giving them `timeout` to finish a commit they're in the middle of.
+    /// Events other than worker exits are dropped, another signal stops waiting right away.
+    pub fn shutdown(&mut self, events: &Receiver<Event>, timeout: Duration) {
+        // left behind ones were told to stop already
+        for t in self.workers.values().filter_map(|w| w.thread.as_ref()) {
+            let _ = t.tx.send(WorkerMsg::Stop);
+        }
+        let threads = std::mem::take(&mut self.workers)
+            .into_iter()
+            .filter_map(|(name, w)| Some((name, w.thread?)))
+            .chain(std::mem::take(&mut self.left_behind))
+            .collect::<Vec<(String, Thread)>>();
+        let mut running = threads.iter().map(|(_, t)| t.id).collect::<BTreeSet<u64>>();
+        let deadline = Instant::now() + timeout;
+        while !running.is_empty() {
+            match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Event::WorkerExited(exit)) => {
+                    running.remove(&exit.id);
+                }
+                Ok(Event::Signal(_)) => {
+                    warn!("Signal received again, not waiting for workers anymore.");
+                    break;
+                }
+                Ok(Event::Connection(_)) => {}
+                Err(_) => break,
+            }
+        }
+        for (name, t) in threads {
+            if running.contains(&t.id) {
+                warn!("Worker of {} didn't stop in time.", name);
+            } else {
+                let _ = t.handle.join();
+            }
+        }
+    }
+}
+
+/// The message a panic was started with, if it has one
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
@@ -0,0 +1,128 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+/// Parses a point in time given on the command line.
+///
+/// Accepted forms:
+/// - `2022-10-20T14:00:00+02:00` (RFC 3339)
+/// - `2022-10-20 14:00:00`, `2022-10-20 14:00` & `2022-10-20` in local time
+/// - `@1666267200` seconds since the epoch
+/// - `1 hour ago`, `1h`, `30 min`, `2 days ago`, ... relative to now
+pub fn parse(s: &str) -> Result<DateTime<Local>, String> {
+    let s = s.trim();
+    if s == "now" {
+        return Ok(Local::now());
+    }
+    if let Some(d) = parse_relative(s) {
+        return Local::now()
+            .checked_sub_signed(d)
+            .ok_or_else(|| format!("{:?} is too far back", s));
+    }
+    if let Some(secs) = s.strip_prefix('@') {
+        return secs
+            .parse::<i64>()
+            .ok()
+            .and_then(|n| Local.timestamp_opt(n, 0).single())
+            .ok_or_else(|| format!("invalid timestamp {:?}", s));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Local));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| {
+            format!(
+                "invalid time {:?}, expected e.g. \"2022-10-20 14:00\" or \"1 hour ago\"",
+                s
+            )
+        })?;
+    // earliest() picks a side when a DST change makes the local time ambiguous
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{:?} doesn't exist in the local timezone", s))
+}
+
+/// `<n><unit>` with an optional space in between & an optional trailing `ago`
+fn parse_relative(s: &str) -> Option<Duration> {
+    let s = s.strip_suffix("ago").unwrap_or(s).trim_end();
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let n = s[..split].parse::<i64>().ok()?;
+    let unit = match s[split..].trim_start() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 60 * 60,
+        "d" | "day" | "days" => 24 * 60 * 60,
+        "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    // Duration panics on anything near i64::MAX milliseconds
+    n.checked_mul(unit)
+        .filter(|secs| *secs < i64::MAX / 1000)
+        .map(Duration::seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(s: &str) -> DateTime<Local> {
+        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap()
+    }
+
+    #[test]
+    fn absolute() {
+        assert_eq!(
+            parse("2022-10-20 14:00:30"),
+            Ok(local("2022-10-20 14:00:30"))
+        );
+        assert_eq!(parse("2022-10-20 14:00"), Ok(local("2022-10-20 14:00:00")));
+        assert_eq!(parse(" 2022-10-20 "), Ok(local("2022-10-20 00:00:00")));
+    }
+
+    #[test]
+    fn rfc3339() {
+        let t = parse("2022-10-20T14:00:00+02:00").unwrap();
+        assert_eq!(t.timestamp(), 1666267200);
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(parse("@1666267200").unwrap().timestamp(), 1666267200);
+        assert!(parse("@soon").is_err());
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(parse_relative("1 hour ago"), Some(Duration::hours(1)));
+        assert_eq!(parse_relative("1h"), Some(Duration::hours(1)));
+        assert_eq!(parse_relative("30 min"), Some(Duration::minutes(30)));
+        assert_eq!(parse_relative("2 days ago"), Some(Duration::days(2)));
+        assert_eq!(parse_relative("3w"), Some(Duration::weeks(3)));
+        assert_eq!(parse_relative("10 fortnights"), None);
+        assert_eq!(parse_relative("hour ago"), None);
+
+        let before = Local::now() - Duration::hours(1);
+        let t = parse("1 hour ago").unwrap();
+        assert!(t >= before && t <= Local::now() - Duration::minutes(59));
+    }
+
+    #[test]
+    fn huge_relative_doesnt_panic() {
+        assert_eq!(parse_relative("9223372036854775807 weeks"), None);
+        assert!(parse("99999999999999 days ago").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse("yesterday-ish").is_err());
+        assert!(parse("2022-13-01").is_err());
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    str::FromStr,
+    time::Duration,
+};
+
+use cbak_config::{DirConfig, VcsKind};
+use fancy_regex::Regex;
+use git2::{IndexAddOption, Repository, Signature};
+use log::{debug, error, info, warn};
+
+use crate::watcher::{get_all_files_nfiltered, matches};
+
+/// The version control operations a worker needs
+pub trait Vcs {
+    /// Stages every change in the watched directory, except ignored paths, and commits it.
+    /// Returns the id of the new commit, or `None` if there was nothing to commit.
+    fn commit_all(&mut self, message: &str) -> Result<Option<String>, Box<dyn Error>> {
+        self.commit_as(message, None)
+    }
+
+    /// Like `commit_all`, with `author` in place of the identity cbakd commits with
+    fn commit_as(
+        &mut self,
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Creates an annotated tag on the last commit & returns the id of that commit
+    fn tag(&mut self, name: &str, message: &str) -> Result<String, Box<dyn Error>>;
+}
+
+/// Author of a manual commit, given as `Name <email>` like `git commit --author`
+#[derive(Debug, Clone)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+impl FromStr for Author {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid author {:?}, expected \"Name <email>\"", s);
+        let (name, rest) = s.split_once('<').ok_or_else(invalid)?;
+        let email = rest.strip_suffix('>').ok_or_else(invalid)?;
+        let name = name.trim();
+        if name.is_empty() || email.is_empty() || email.contains(['<', '>']) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            email: email.to_string(),
+        })
+    }
+}
+
+impl Display for Author {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+/// Committer name when git has none configured
+const FALLBACK_NAME: &str = "cbakd";
+
+/// cbakd@hostname, the committer email when git has none configured
+fn fallback_email() -> String {
+    let hostname = hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "localhost".to_string());
+    format!("cbakd@{}", hostname)
+}
+
+/// Opens the repository for a watch entry with the configured backend, creating it if needed
+pub fn open(config: &DirConfig) -> Result<Box<dyn Vcs + Send>, Box<dyn Error>> {
+    Ok(match config.vcs {
+        VcsKind::Libgit2 => Box::new(LibGit::open(config)?),
+        VcsKind::Git => Box::new(GitCli::open(config)?),
+    })
+}
+
+/// How old an index.lock has to be before cbakd takes it for a leftover.
+/// A younger one may belong to git run by hand or to another cbakd that is still committing
+const STALE_LOCK_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Removes a lock git leaves behind when it is killed halfway through a commit, which would make every later commit fail
+pub fn remove_stale_lock(config: &DirConfig) {
+    let lock = config.git_dir.join("index.lock");
+    let Ok(age) = fs::symlink_metadata(&lock).and_then(|m| m.modified()) else {
+        return;
+    };
+    if age.elapsed().unwrap_or_default() < STALE_LOCK_AGE {
+        warn!(
+            "{} is in use or was left behind only recently, commits fail until it is gone",
+            lock.display()
+        );
+        return;
+    }
+    warn!("Removing stale lock {}", lock.display());
+    if let Err(e) = fs::remove_file(&lock) {
+        error!("Could not remove {}.", lock.display());
+        debug!("{}", e);
+    }
+}
+
+/// In-process backend built on libgit2
+pub struct LibGit {
+    repo: Repository,
+    directory: String,
+    ignore: Vec<Regex>,
+}
+
+impl LibGit {
+    pub fn open(config: &DirConfig) -> Result<Self, Box<dyn Error>> {
+        let repo = match Repository::open(&config.git_dir) {
+            Ok(r) => r,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                info!(
+                    "Initialising repository {} for {}",
+                    config.git_dir.display(),
+                    config.directory
+                );
+                if let Some(parent) = config.git_dir.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // init as bare & attach the work tree afterwards,
+                // initialising with a workdir would drop a .git link file into the watched directory
+                let repo = Repository::init_bare(&config.git_dir)?;
+                let mut c = repo.config()?;
+                c.set_bool("core.bare", false)?;
+                c.set_str("core.worktree", &config.directory)?;
+                Repository::open(&config.git_dir)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // don't rely on core.worktree, the directory may have been moved in the config since
+        repo.set_workdir(Path::new(&config.directory), false)?;
+        Ok(Self {
+            repo,
+            directory: config.directory.clone(),
+            ignore: config.ignore.clone(),
+        })
+    }
+
+    /// Identity used for commits, falls back to cbakd@hostname if git has no user configured
+    fn signature(&self) -> Result<Signature<'static>, git2::Error> {
+        match self.repo.signature() {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Signature::now(FALLBACK_NAME, &fallback_email()),
+        }
+    }
+}
+
+impl Vcs for LibGit {
+    fn commit_as(
+        &mut self,
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let root = Path::new(&self.directory);
+        let ignore = &self.ignore;
+        let ignored = |p: &Path| matches(root.join(p).to_str().unwrap_or(""), ignore);
+
+        let mut index = self.repo.index()?;
+        // callbacks return 0 to process a path & 1 to skip it
+        index.add_all(
+            ["*"].iter(),
+            IndexAddOption::DEFAULT,
+            Some(&mut |p: &Path, _: &[u8]| ignored(p) as i32),
+        )?;
+        index.update_all(
+            ["*"].iter(),
+            Some(&mut |p: &Path, _: &[u8]| ignored(p) as i32),
+        )?;
+        // drop anything that was tracked before it got ignored
+        index.remove_all(
+            ["*"].iter(),
+            Some(&mut |p: &Path, _: &[u8]| !ignored(p) as i32),
+        )?;
+        index.write()?;
+
+        let tree = self.repo.find_tree(index.write_tree()?)?;
+        let parent = match self.repo.head() {
+            Ok(h) => Some(h.peel_to_commit()?),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => None,
+            Err(e) => return Err(e.into()),
+        };
+        let unchanged = match &parent {
+            Some(p) => p.tree_id() == tree.id(),
+            None => tree.is_empty(),
+        };
+        if unchanged {
+            debug!("Nothing to commit in {}", self.directory);
+            return Ok(None);
+        }
+
+        let sig = self.signature()?;
+        let author = match author {
+            Some(a) => Signature::now(&a.name, &a.email)?,
+            None => sig.clone(),
+        };
+        let id = self.repo.commit(
+            Some("HEAD"),
+            &author,
+            &sig,
+            message,
+            &tree,
+            parent.iter().collect::<Vec<_>>().as_slice(),
+        )?;
+        Ok(Some(id.to_string()))
+    }
+
+    fn tag(&mut self, name: &str, message: &str) -> Result<String, Box<dyn Error>> {
+        let head = match self.repo.head() {
+            Ok(h) => h.peel_to_commit()?,
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => {
+                return Err("nothing has been committed yet".into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let sig = self.signature()?;
+        self.repo
+            .tag(name, head.as_object(), &sig, message, false)
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::Exists => format!("tag {} already exists", name).into(),
+                _ => Box::<dyn Error>::from(e),
+            })?;
+        Ok(head.id().to_string())
+    }
+}
+
+/// A git command that didn't succeed
+#[derive(Debug)]
+pub struct VcsError {
+    pub step: String,
+    pub status: Option<i32>,
+    pub stderr: String,
+}
+
+impl Display for VcsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(c) => write!(f, "{} exited with status {}", self.step, c)?,
+            None => write!(f, "{} was killed by a signal", self.step)?,
+        }
+        // git puts the actual problem on the first line, the rest is advice
+        if let Some(line) = self.stderr.lines().next() {
+            write!(f, ": {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for VcsError {}
+
+/// Backend that runs the git binary
+pub struct GitCli {
+    directory: String,
+    git_dir: PathBuf,
+    ignore: Vec<Regex>,
+}
+
+impl GitCli {
+    pub fn open(config: &DirConfig) -> Result<Self, Box<dyn Error>> {
+        let cli = Self {
+            directory: config.directory.clone(),
+            git_dir: config.git_dir.clone(),
+            ignore: config.ignore.clone(),
+        };
+        if !config.git_dir.join("HEAD").exists() {
+            info!(
+                "Initialising repository {} for {}",
+                config.git_dir.display(),
+                config.directory
+            );
+            if let Some(parent) = config.git_dir.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            cli.run("git init", cli.git().arg("init"))?;
+        }
+        Ok(cli)
+    }
+
+    fn git(&self) -> Command {
+        let mut c = Command::new("git");
+        c.arg("--git-dir")
+            .arg(&self.git_dir)
+            .arg("--work-tree")
+            .arg(&self.directory)
+            .current_dir(&self.directory);
+        c
+    }
+
+    /// Runs a git command, turning a non-zero exit status into an error carrying its stderr
+    fn run(&self, step: &str, cmd: &mut Command) -> Result<Output, Box<dyn Error>> {
+        let out = cmd
+            .output()
+            .map_err(|e| format!("Could not run {}, do you have git installed? ({})", step, e))?;
+        if !out.status.success() {
+            return Err(Box::new(VcsError {
+                step: step.to_string(),
+                status: out.status.code(),
+                stderr: String::from_utf8_lossy(&out.stderr).trim().to_string(),
+            }));
+        }
+        Ok(out)
+    }
+
+    /// `git()` with cbakd@hostname filled in for whatever part of the identity git has no config for,
+    /// the same fallback `LibGit` uses. git refuses to commit or tag without one
+    fn identified(&self) -> Command {
+        let mut c = self.git();
+        let configured = |key: &str| {
+            self.git()
+                .args(["config", key])
+                .output()
+                .is_ok_and(|o| o.status.success() && !o.stdout.trim_ascii().is_empty())
+        };
+        if !configured("user.name") {
+            c.arg("-c").arg(format!("user.name={}", FALLBACK_NAME));
+        }
+        if !configured("user.email") {
+            c.arg("-c").arg(format!("user.email={}", fallback_email()));
+        }
+        c
+    }
+
+    /// Id of the last commit
+    fn head(&self) -> Result<String, Box<dyn Error>> {
+        let head = self.run(
+            "git rev-parse",
+            self.git().args(["rev-parse", "--verify", "HEAD"]),
+        )?;
+        Ok(String::from_utf8_lossy(&head.stdout).trim().to_string())
+    }
+}
+
+impl Vcs for GitCli {
+    fn commit_as(
+        &mut self,
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        self.run("git add", self.git().arg("add").arg("-A"))?;
+
+        let ignored = get_all_files_nfiltered(Path::new(&self.directory), &self.ignore)?;
+        let ignored = ignored
+            .contents
+            .iter()
+            .chain(ignored.subdirs.iter())
+            .filter_map(|i| i.strip_prefix(&self.directory).ok()?.to_str())
+            .collect::<Vec<&str>>();
+        if !ignored.is_empty() {
+            self.run(
+                "git rm",
+                self.git()
+                    .args(["rm", "-r", "-f", "--cached", "--ignore-unmatch", "--"])
+                    .args(ignored),
+            )?;
+        }
+
+        // exits with 0 if nothing is staged
+        let staged = self.git().args(["diff", "--cached", "--quiet"]).status()?;
+        if staged.success() {
+            debug!("Nothing to commit in {}", self.directory);
+            return Ok(None);
+        }
+
+        let mut commit = self.identified();
+        commit.arg("commit").args(["-m", message]);
+        if let Some(a) = author {
+            commit.arg(format!("--author={}", a));
+        }
+        self.run("git commit", &mut commit)?;
+
+        Ok(Some(self.head()?))
+    }
+
+    fn tag(&mut self, name: &str, message: &str) -> Result<String, Box<dyn Error>> {
+        let head = self.head()?;
+        self.run(
+            "git tag",
+            self.identified()
+                .args(["tag", "-a", "-m", message, "--", name]),
+        )?;
+        Ok(head)
+    }
+}
@@ -0,0 +1,879 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, Sender, TryRecvError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use cbak_config::{DirConfig, WatcherKind};
+use fancy_regex::Regex;
+use inotify::{EventMask, EventOwned, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, trace, warn};
+use rayon::prelude::*;
+
+use crate::{restore::Restored, vcs::Author};
+
+/// How often a watcher checks its control channel while nothing is happening on disk
+const TICK: Duration = Duration::from_millis(250);
+
+/// A single change to a file in a watched directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileChanges {
+    Created(PathBuf),
+    Deleted(PathBuf),
+    /// from, to
+    Renamed(PathBuf, PathBuf),
+    Modified(PathBuf),
+}
+
+impl FileChanges {
+    /// Where the file is now, or was before it got deleted
+    pub fn path(&self) -> &Path {
+        match self {
+            FileChanges::Created(p)
+            | FileChanges::Deleted(p)
+            | FileChanges::Modified(p)
+            | FileChanges::Renamed(_, p) => p,
+        }
+    }
+}
+
+/// Holds all the contents of a directory
+/// contents is the pooled contents of all of the subdirs & the root directory (excluding dirs)
+#[derive(Debug)]
+pub struct DirContents {
+    pub root: PathBuf,
+    pub subdirs: Vec<PathBuf>,
+    pub contents: Vec<PathBuf>,
+}
+
+/// Requests the daemon sends to a worker
+#[derive(Debug)]
+pub enum WorkerMsg {
+    Stop,
+    /// Continue with a changed config that doesn't need a new watcher or repository, e.g. another commit message
+    Reconfigure(DirConfig),
+    /// Put files back the way they were at `rev`, see `restore::restore`
+    Restore {
+        rev: String,
+        /// Relative to the watched directory, everything if empty
+        paths: Vec<String>,
+        reply: Sender<Result<Restored, String>>,
+    },
+    /// Commit everything right away with `message`, replying with the id of the new commit
+    Commit {
+        message: String,
+        author: Option<Author>,
+        reply: Sender<Result<Option<String>, String>>,
+    },
+    /// Commit pending changes & tag the result, replying with the id of the tagged commit
+    Tag {
+        name: String,
+        message: String,
+        reply: Sender<Result<String, String>>,
+    },
+    /// Keep detecting changes but don't commit them, until resumed or `duration` is over
+    Pause {
+        duration: Option<Duration>,
+    },
+    /// Sent when the `duration` of a pause is over, ignored if the worker has been resumed or paused again since
+    PauseExpired,
+    /// End a pause, committing what changed in the meantime if `commit` is set
+    Resume {
+        commit: bool,
+    },
+}
+
+/// What woke a worker up
+#[derive(Debug)]
+pub enum Wake {
+    Changes(Vec<FileChanges>),
+    Message(WorkerMsg),
+}
+
+/// A source of change notifications for one watched directory
+pub trait Watcher {
+    /// Blocks until something in the watched directory changes or a message arrives on `rx`.
+    /// A closed channel is reported as `WorkerMsg::Stop`.
+    fn wait(&mut self, rx: &Receiver<WorkerMsg>) -> Result<Wake, Box<dyn Error>>;
+
+    /// Forgets everything that happened since the last `wait`, used after the worker wrote to the directory itself
+    fn reset(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// Folds consecutive batches of changes into one, as if they had been reported together
+pub fn coalesce(changes: impl IntoIterator<Item = FileChanges>) -> Vec<FileChanges> {
+    let mut set = ChangeSet::default();
+    changes.into_iter().for_each(|c| set.add(c));
+    set.into_changes()
+}
+
+/// Turns files reported as created into modified ones if `existed` says they were there before, which a watcher
+/// can't tell when a new file is renamed over an existing one (e.g. an editor saving through a temporary file).
+/// Modified files that weren't there, e.g. before the first commit, are turned into created ones
+pub fn reclassify(changes: Vec<FileChanges>, existed: impl Fn(&Path) -> bool) -> Vec<FileChanges> {
+    changes
+        .into_iter()
+        .map(|c| match c {
+            FileChanges::Created(p) if existed(&p) => FileChanges::Modified(p),
+            FileChanges::Modified(p) if !existed(&p) => FileChanges::Created(p),
+            c => c,
+        })
+        .collect()
+}
+
+/// Checks for a message without blocking
+fn poll_channel(rx: &Receiver<WorkerMsg>) -> Option<WorkerMsg> {
+    match rx.try_recv() {
+        Ok(m) => Some(m),
+        Err(TryRecvError::Disconnected) => Some(WorkerMsg::Stop),
+        Err(TryRecvError::Empty) => None,
+    }
+}
+
+/// Creates the watcher backend selected for a `[[watch]]` block, falling back to polling
+/// if inotify can't be set up (e.g. the watch limit is exhausted).
+pub fn new_watcher(config: &DirConfig) -> Box<dyn Watcher + Send> {
+    match config.watcher {
+        WatcherKind::Inotify => match InotifyWatcher::new(config) {
+            Ok(w) => Box::new(w),
+            Err(e) => {
+                warn!(
+                    "Could not set up inotify for {}, falling back to polling.",
+                    config.directory
+                );
+                debug!("{}", e);
+                Box::new(PollWatcher::new(config))
+            }
+        },
+        WatcherKind::Poll => Box::new(PollWatcher::new(config)),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum PathState {
+    Created,
+    Deleted,
+    Modified,
+    RenamedFrom(PathBuf),
+}
+
+/// Collects the changes of one batch, folding repeated events for the same path together
+/// (e.g. a file that is created and then deleted again before the batch ends is dropped).
+#[derive(Debug, Default)]
+struct ChangeSet {
+    paths: BTreeMap<PathBuf, PathState>,
+}
+
+impl ChangeSet {
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    fn created(&mut self, path: PathBuf) {
+        let state = match self.paths.get(&path) {
+            // deleted & recreated, e.g. an editor replacing the file
+            Some(PathState::Deleted) => PathState::Modified,
+            Some(s) => s.clone(),
+            None => PathState::Created,
+        };
+        self.paths.insert(path, state);
+    }
+
+    fn modified(&mut self, path: PathBuf) {
+        self.paths.entry(path).or_insert(PathState::Modified);
+    }
+
+    fn deleted(&mut self, path: PathBuf) {
+        match self.paths.remove(&path) {
+            Some(PathState::Created) => {}
+            Some(PathState::RenamedFrom(from)) => self.gone(from),
+            _ => {
+                self.paths.insert(path, PathState::Deleted);
+            }
+        }
+    }
+
+    /// A path that existed before the batch was moved away, it's deleted unless something took its place since
+    fn gone(&mut self, path: PathBuf) {
+        match self.paths.get(&path) {
+            None => {
+                self.paths.insert(path, PathState::Deleted);
+            }
+            // e.g. vim moving the file to a backup & writing a new one
+            Some(PathState::Created) => {
+                self.paths.insert(path, PathState::Modified);
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn renamed(&mut self, from: PathBuf, to: PathBuf) {
+        let state = match self.paths.remove(&from) {
+            Some(PathState::Created) => PathState::Created,
+            Some(PathState::RenamedFrom(orig)) => PathState::RenamedFrom(orig),
+            _ => PathState::RenamedFrom(from),
+        };
+        // whether `to` existed before the batch & is replaced now
+        let replaced = match self.paths.remove(&to) {
+            Some(PathState::Modified | PathState::Deleted) => true,
+            // the file renamed to `to` earlier is overwritten
+            Some(PathState::RenamedFrom(prev)) => {
+                self.gone(prev);
+                false
+            }
+            Some(PathState::Created) | None => false,
+        };
+        let state = match state {
+            // moved away & back again
+            PathState::RenamedFrom(orig) if orig == to => PathState::Modified,
+            PathState::RenamedFrom(orig) if replaced => {
+                self.gone(orig);
+                PathState::Modified
+            }
+            PathState::Created if replaced => PathState::Modified,
+            s => s,
+        };
+        self.paths.insert(to, state);
+    }
+
+    fn add(&mut self, change: FileChanges) {
+        match change {
+            FileChanges::Created(p) => self.created(p),
+            FileChanges::Deleted(p) => self.deleted(p),
+            FileChanges::Modified(p) => self.modified(p),
+            FileChanges::Renamed(from, to) => self.renamed(from, to),
+        }
+    }
+
+    fn into_changes(self) -> Vec<FileChanges> {
+        self.paths
+            .into_iter()
+            .map(|(path, state)| match state {
+                PathState::Created => FileChanges::Created(path),
+                PathState::Deleted => FileChanges::Deleted(path),
+                PathState::Modified => FileChanges::Modified(path),
+                PathState::RenamedFrom(from) => FileChanges::Renamed(from, path),
+            })
+            .collect()
+    }
+}
+
+/// Event driven watcher built on inotify.
+/// Every non-ignored directory in the tree gets its own watch, directories created later are registered as they appear.
+pub struct InotifyWatcher {
+    inotify: Inotify,
+    root: PathBuf,
+    ignore: Vec<Regex>,
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    write_delay: Duration,
+}
+
+impl InotifyWatcher {
+    const MASK: WatchMask = WatchMask::MODIFY
+        .union(WatchMask::CLOSE_WRITE)
+        .union(WatchMask::ATTRIB)
+        .union(WatchMask::CREATE)
+        .union(WatchMask::DELETE)
+        .union(WatchMask::MOVED_FROM)
+        .union(WatchMask::MOVED_TO);
+
+    pub fn new(config: &DirConfig) -> io::Result<Self> {
+        let mut w = Self {
+            inotify: Inotify::init()?,
+            root: PathBuf::from(&config.directory),
+            ignore: config.ignore.clone(),
+            watches: HashMap::new(),
+            write_delay: Duration::from_secs(config.write_delay as u64),
+        };
+        w.add_recursive(&w.root.clone())?;
+        debug!(
+            "Registered {} inotify watches for {}",
+            w.watches.len(),
+            config.directory
+        );
+        Ok(w)
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        matches(path.to_str().unwrap_or(""), &self.ignore)
+    }
+
+    /// Adds a watch for `dir` and every directory below it.
+    /// Returns the files found on the way, they may have been written before the watch existed.
+    fn add_recursive(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        if self.is_ignored(dir) {
+            return Ok(vec![]);
+        }
+        let wd = self.inotify.watches().add(dir, Self::MASK)?;
+        self.watches.insert(wd, dir.to_path_buf());
+
+        let mut files = vec![];
+        for entry in dir.read_dir()?.flatten() {
+            // don't follow symlinks, they may point outside of the watched tree
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                match self.add_recursive(&entry.path()) {
+                    Ok(mut f) => files.append(&mut f),
+                    // the directory may have been removed again before we got to it
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            } else if !self.is_ignored(&entry.path()) {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Drops the watches of a directory that left the tree
+    fn remove_recursive(&mut self, dir: &Path) {
+        let gone = self
+            .watches
+            .iter()
+            .filter(|(_, p)| p.starts_with(dir))
+            .map(|(wd, _)| wd.clone())
+            .collect::<Vec<WatchDescriptor>>();
+        for wd in gone {
+            // fails if the kernel already dropped the watch, which is fine
+            let _ = self.inotify.watches().remove(wd.clone());
+            self.watches.remove(&wd);
+        }
+    }
+
+    /// Points the watches below a renamed directory at its new location
+    fn rename_watches(&mut self, from: &Path, to: &Path) {
+        for p in self.watches.values_mut() {
+            if let Ok(rest) = p.strip_prefix(from) {
+                *p = to.join(rest);
+            }
+        }
+    }
+
+    /// Reads every queued event without blocking
+    fn read_pending(&mut self) -> io::Result<Vec<EventOwned>> {
+        let mut buffer = [0; 4096];
+        let mut out = vec![];
+        loop {
+            match self.inotify.read_events(&mut buffer) {
+                Ok(events) => {
+                    let before = out.len();
+                    out.extend(events.map(|e| e.to_owned()));
+                    if out.len() == before {
+                        return Ok(out);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(out),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Adds a directory that appeared in the tree, reporting everything already inside it as created
+    fn new_directory(&mut self, path: &Path, changes: &mut ChangeSet) {
+        match self.add_recursive(path) {
+            Ok(files) => files.into_iter().for_each(|f| changes.created(f)),
+            Err(e) => {
+                warn!("Could not watch new directory {}.", path.display());
+                debug!("{}", e);
+            }
+        }
+    }
+}
+
+impl Watcher for InotifyWatcher {
+    fn wait(&mut self, rx: &Receiver<WorkerMsg>) -> Result<Wake, Box<dyn Error>> {
+        let mut changes = ChangeSet::default();
+        // MOVED_FROM events waiting for their MOVED_TO, keyed by cookie
+        let mut moves: HashMap<u32, (PathBuf, bool)> = HashMap::new();
+        let mut last_event: Option<Instant> = None;
+
+        loop {
+            if let Some(m) = poll_channel(rx) {
+                return Ok(Wake::Message(m));
+            }
+
+            for event in self.read_pending()? {
+                let (wd, mask, cookie, name) = (event.wd, event.mask, event.cookie, event.name);
+                trace!("inotify: {:?} {:?}", mask, name);
+                if mask.contains(EventMask::Q_OVERFLOW) {
+                    // Events were dropped, we can't tell what happened so treat every directory as modified
+                    warn!("inotify queue overflowed, some events were lost.");
+                    self.watches
+                        .values()
+                        .for_each(|p| changes.modified(p.clone()));
+                    last_event = Some(Instant::now());
+                    continue;
+                }
+                if mask.contains(EventMask::IGNORED) {
+                    self.watches.remove(&wd);
+                    continue;
+                }
+
+                let path = match (self.watches.get(&wd), name) {
+                    (Some(d), Some(n)) => d.join(n),
+                    _ => continue,
+                };
+                let is_dir = mask.contains(EventMask::ISDIR);
+                last_event = Some(Instant::now());
+
+                if mask.contains(EventMask::MOVED_FROM) {
+                    moves.insert(cookie, (path, is_dir));
+                    continue;
+                }
+                if mask.contains(EventMask::MOVED_TO) {
+                    match moves.remove(&cookie) {
+                        Some((from, _)) => match (self.is_ignored(&from), self.is_ignored(&path)) {
+                            (false, false) => {
+                                if is_dir {
+                                    self.rename_watches(&from, &path);
+                                }
+                                changes.renamed(from, path);
+                            }
+                            (false, true) => {
+                                if is_dir {
+                                    self.remove_recursive(&from);
+                                }
+                                changes.deleted(from);
+                            }
+                            (true, false) if is_dir => self.new_directory(&path, &mut changes),
+                            (true, false) => changes.created(path),
+                            (true, true) => {}
+                        },
+                        // moved in from outside of the tree
+                        None if self.is_ignored(&path) => {}
+                        None if is_dir => self.new_directory(&path, &mut changes),
+                        None => changes.created(path),
+                    }
+                    continue;
+                }
+
+                if self.is_ignored(&path) {
+                    continue;
+                }
+                if is_dir {
+                    // removed directories have already reported their contents
+                    if mask.contains(EventMask::CREATE) {
+                        self.new_directory(&path, &mut changes);
+                    }
+                } else if mask.contains(EventMask::CREATE) {
+                    changes.created(path);
+                } else if mask.contains(EventMask::DELETE) {
+                    changes.deleted(path);
+                } else {
+                    changes.modified(path);
+                }
+            }
+
+            // wait for writes to settle before reporting
+            if let Some(t) = last_event {
+                if t.elapsed() >= self.write_delay {
+                    // anything still unpaired was moved out of the tree
+                    for (_, (from, is_dir)) in moves.drain() {
+                        if is_dir {
+                            self.remove_recursive(&from);
+                        }
+                        if !self.is_ignored(&from) {
+                            changes.deleted(from);
+                        }
+                    }
+                    if changes.is_empty() {
+                        last_event = None;
+                    } else {
+                        return Ok(Wake::Changes(changes.into_changes()));
+                    }
+                }
+            }
+
+            std::thread::sleep(TICK);
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        for event in self.read_pending()? {
+            if event.mask.contains(EventMask::IGNORED) {
+                self.watches.remove(&event.wd);
+            }
+        }
+        // directories may have appeared, adding an existing watch again is a no-op
+        self.add_recursive(&self.root.clone())?;
+        Ok(())
+    }
+}
+
+/// Watcher that periodically compares modification times, used where inotify isn't available
+pub struct PollWatcher {
+    root: PathBuf,
+    ignore: Vec<Regex>,
+    poll_interval: Duration,
+    write_delay: Duration,
+    /// File -> (inode, mtime) as of the last reported change
+    snapshot: HashMap<PathBuf, (u64, SystemTime)>,
+}
+
+impl PollWatcher {
+    pub fn new(config: &DirConfig) -> Self {
+        let mut w = Self {
+            root: PathBuf::from(&config.directory),
+            ignore: config.ignore.clone(),
+            poll_interval: Duration::from_secs(config.poll_interval as u64),
+            write_delay: Duration::from_secs(config.write_delay as u64),
+            snapshot: HashMap::new(),
+        };
+        w.snapshot = w.scan().unwrap_or_default();
+        w
+    }
+
+    /// Stats every file in the tree, skipping anything that disappears while we look at it
+    fn scan(&self) -> io::Result<HashMap<PathBuf, (u64, SystemTime)>> {
+        Ok(get_all_files_filtered(&self.root, &self.ignore)?
+            .contents
+            .into_par_iter()
+            .filter_map(|p| {
+                let meta = p.symlink_metadata().ok()?;
+                Some((p, (meta.ino(), meta.modified().ok()?)))
+            })
+            .collect())
+    }
+
+    /// Compares a scan against the last snapshot, pairing up deleted & created paths with the same inode as renames
+    fn diff(&self, new: &HashMap<PathBuf, (u64, SystemTime)>) -> Vec<FileChanges> {
+        let mut changes = ChangeSet::default();
+        let mut deleted = self
+            .snapshot
+            .iter()
+            .filter(|(p, _)| !new.contains_key(*p))
+            .map(|(p, (ino, _))| (*ino, p.clone()))
+            .collect::<HashMap<u64, PathBuf>>();
+
+        for (path, (ino, mtime)) in new {
+            match self.snapshot.get(path) {
+                Some(old) if old == &(*ino, *mtime) => {}
+                Some(_) => changes.modified(path.clone()),
+                None => match deleted.remove(ino) {
+                    Some(from) => changes.renamed(from, path.clone()),
+                    None => changes.created(path.clone()),
+                },
+            }
+        }
+        deleted.into_values().for_each(|p| changes.deleted(p));
+        changes.into_changes()
+    }
+}
+
+impl Watcher for PollWatcher {
+    fn wait(&mut self, rx: &Receiver<WorkerMsg>) -> Result<Wake, Box<dyn Error>> {
+        let mut last_poll = Instant::now();
+        loop {
+            // check the channel every tick so a stop request doesn't wait for a whole poll interval
+            std::thread::sleep(TICK);
+            if let Some(m) = poll_channel(rx) {
+                return Ok(Wake::Message(m));
+            }
+            if last_poll.elapsed() < self.poll_interval {
+                continue;
+            }
+            last_poll = Instant::now();
+
+            let mut current = self.scan()?;
+            if current == self.snapshot {
+                continue;
+            }
+
+            // keep rescanning until writes have settled, the snapshot stays as it is
+            // if a message comes in meanwhile so the changes are found again next time
+            loop {
+                let settle = Instant::now();
+                while settle.elapsed() < self.write_delay {
+                    std::thread::sleep(TICK);
+                    if let Some(m) = poll_channel(rx) {
+                        return Ok(Wake::Message(m));
+                    }
+                }
+                let next = self.scan()?;
+                if next == current {
+                    break;
+                }
+                current = next;
+            }
+
+            let changes = self.diff(&current);
+            self.snapshot = current;
+            if !changes.is_empty() {
+                return Ok(Wake::Changes(changes));
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.snapshot = self.scan()?;
+        Ok(())
+    }
+}
+
+/// Gets all the files in a directory, within a DirContents struct, filtered by the ignore param
+pub fn get_all_files_filtered(dir: &Path, ignore: &Vec<Regex>) -> std::io::Result<DirContents> {
+    let r = dir.read_dir()?;
+    let mut paths = Vec::new();
+    let mut subdirs = Vec::new();
+    for file in r {
+        match file {
+            Ok(f) => {
+                match f.path().is_dir() {
+                    // nothing below an ignored directory is kept, no need to walk it
+                    true if matches(&f.path().to_string_lossy(), ignore) => {}
+                    true => {
+                        let mut r = get_all_files_filtered(&f.path(), ignore)?;
+
+                        // Add subdirs to list
+                        subdirs.push(r.root);
+                        subdirs.append(&mut r.subdirs);
+
+                        paths.append(&mut r.contents);
+                    }
+                    false => paths.push(f.path()),
+                }
+            }
+            Err(_) => continue,
+        }
+    }
+    // Remvoe duplicate subdirs
+    subdirs.sort();
+    subdirs.dedup();
+
+    Ok(DirContents {
+        root: dir.to_path_buf(),
+        subdirs: subdirs
+            .par_iter()
+            .filter(|p| !matches(&p.to_string_lossy(), ignore))
+            .map(|p| p.to_owned())
+            .collect::<Vec<PathBuf>>(),
+        contents: paths
+            .par_iter()
+            .filter(|p| !matches(&p.to_string_lossy(), ignore))
+            .map(|p| p.to_owned())
+            .collect::<Vec<PathBuf>>(),
+    })
+}
+
+/// Gets all the files in a directory, within a DirContents struct, that would of been removed by the ignore param
+pub fn get_all_files_nfiltered(dir: &Path, ignore: &Vec<Regex>) -> std::io::Result<DirContents> {
+    let r = dir.read_dir()?;
+    let mut paths = Vec::new();
+    let mut subdirs = Vec::new();
+    for file in r {
+        match file {
+            Ok(f) => {
+                match f.path().is_dir() {
+                    // an ignored directory is reported as a whole instead of file by file
+                    true if matches(&f.path().to_string_lossy(), ignore) => subdirs.push(f.path()),
+                    true => {
+                        let mut r = get_all_files_nfiltered(&f.path(), ignore)?;
+                        // Add subdirs to list
+                        subdirs.push(r.root);
+                        subdirs.append(&mut r.subdirs);
+
+                        paths.append(&mut r.contents);
+                    }
+                    false => paths.push(f.path()),
+                }
+            }
+            Err(_) => continue,
+        }
+    }
+    // Remvoe duplicate subdirs
+    subdirs.sort();
+    subdirs.dedup();
+
+    Ok(DirContents {
+        root: dir.to_path_buf(),
+        subdirs: subdirs
+            .par_iter()
+            .filter(|p| matches(&p.to_string_lossy(), ignore))
+            .map(|p| p.to_owned())
+            .collect::<Vec<PathBuf>>(),
+        contents: paths
+            .par_iter()
+            .filter(|p| matches(&p.to_string_lossy(), ignore))
+            .map(|p| p.to_owned())
+            .collect::<Vec<PathBuf>>(),
+    })
+}
+
+/// True if any of the ignore patterns match the path.
+/// Paths that aren't UTF-8 are matched with `to_string_lossy`, bytes that aren't valid become U+FFFD
+pub fn matches(input: &str, pattern: &[Regex]) -> bool {
+    pattern.iter().any(|f| f.is_match(input).unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+
+    use super::*;
+
+    fn p(s: &str) -> PathBuf {
+        PathBuf::from(s)
+    }
+
+    #[test]
+    fn vim_backup_rename() {
+        // backupcopy=no: the file becomes the backup, a new one is written & the backup deleted
+        let changes = coalesce([
+            FileChanges::Renamed(p("/w/f"), p("/w/f~")),
+            FileChanges::Created(p("/w/f")),
+            FileChanges::Modified(p("/w/f")),
+            FileChanges::Deleted(p("/w/f~")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Modified(p("/w/f"))]);
+    }
+
+    #[test]
+    fn vim_swap_file() {
+        let changes = coalesce([
+            FileChanges::Created(p("/w/.f.swp")),
+            FileChanges::Modified(p("/w/f")),
+            FileChanges::Deleted(p("/w/.f.swp")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Modified(p("/w/f"))]);
+    }
+
+    #[test]
+    fn atomic_rename_over_changed_file() {
+        let changes = coalesce([
+            FileChanges::Modified(p("/w/f")),
+            FileChanges::Created(p("/w/.f.tmp")),
+            FileChanges::Renamed(p("/w/.f.tmp"), p("/w/f")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Modified(p("/w/f"))]);
+    }
+
+    #[test]
+    fn atomic_rename_over_committed_file() {
+        // the watcher can't know f existed, the repository can
+        let changes = coalesce([
+            FileChanges::Created(p("/w/.f.tmp")),
+            FileChanges::Modified(p("/w/.f.tmp")),
+            FileChanges::Renamed(p("/w/.f.tmp"), p("/w/f")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Created(p("/w/f"))]);
+        assert_eq!(
+            reclassify(changes, |f| f == Path::new("/w/f")),
+            vec![FileChanges::Modified(p("/w/f"))]
+        );
+    }
+
+    #[test]
+    fn first_commit() {
+        // nothing was committed yet, so everything is new to the repository
+        let changes = coalesce([
+            FileChanges::Modified(p("/w/a.conf")),
+            FileChanges::Created(p("/w/b.conf")),
+        ]);
+        assert_eq!(
+            reclassify(changes, |_| false),
+            vec![
+                FileChanges::Created(p("/w/a.conf")),
+                FileChanges::Created(p("/w/b.conf"))
+            ]
+        );
+    }
+
+    #[test]
+    fn atomic_rename_new_file() {
+        let changes = coalesce([
+            FileChanges::Created(p("/w/.g.tmp")),
+            FileChanges::Renamed(p("/w/.g.tmp"), p("/w/g")),
+        ]);
+        assert_eq!(
+            reclassify(changes, |_| false),
+            vec![FileChanges::Created(p("/w/g"))]
+        );
+    }
+
+    #[test]
+    fn rename_over_existing_file() {
+        let changes = coalesce([
+            FileChanges::Deleted(p("/w/b")),
+            FileChanges::Renamed(p("/w/a"), p("/w/b")),
+        ]);
+        assert_eq!(
+            changes,
+            vec![
+                FileChanges::Deleted(p("/w/a")),
+                FileChanges::Modified(p("/w/b"))
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_and_back() {
+        let changes = coalesce([
+            FileChanges::Renamed(p("/w/a"), p("/w/b")),
+            FileChanges::Renamed(p("/w/b"), p("/w/a")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Modified(p("/w/a"))]);
+    }
+
+    #[test]
+    fn rename_then_delete() {
+        let changes = coalesce([
+            FileChanges::Renamed(p("/w/a"), p("/w/b")),
+            FileChanges::Deleted(p("/w/b")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Deleted(p("/w/a"))]);
+    }
+
+    #[test]
+    fn created_then_deleted() {
+        let changes = coalesce([
+            FileChanges::Created(p("/w/a")),
+            FileChanges::Modified(p("/w/a")),
+            FileChanges::Deleted(p("/w/a")),
+        ]);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn deleted_then_recreated() {
+        let changes = coalesce([
+            FileChanges::Deleted(p("/w/a")),
+            FileChanges::Created(p("/w/a")),
+        ]);
+        assert_eq!(changes, vec![FileChanges::Modified(p("/w/a"))]);
+    }
+
+    #[test]
+    fn batches_coalesce_like_one() {
+        let first = coalesce([FileChanges::Renamed(p("/w/f"), p("/w/f~"))]);
+        let second = coalesce(first.into_iter().chain([
+            FileChanges::Created(p("/w/f")),
+            FileChanges::Deleted(p("/w/f~")),
+        ]));
+        assert_eq!(second, vec![FileChanges::Modified(p("/w/f"))]);
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        let dir = std::env::temp_dir().join(format!("cbakd-{}-non-utf8", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let odd = dir.join("sub").join(OsStr::from_bytes(b"\xff.conf"));
+        fs::write(&odd, "").unwrap();
+        fs::write(dir.join("a.conf"), "").unwrap();
+
+        let ignore = [Regex::new("/a\\.conf$").unwrap()].to_vec();
+        let kept = get_all_files_filtered(&dir, &ignore);
+        let ignored = get_all_files_nfiltered(&dir, &ignore);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(kept.unwrap().contents, [odd]);
+        assert_eq!(ignored.unwrap().contents, [dir.join("a.conf")]);
+
+        // invalid bytes can still be ignored
+        assert!(matches(
+            &Path::new(OsStr::from_bytes(b"/etc/\xff")).to_string_lossy(),
+            &[Regex::new("/etc/\u{FFFD}$").unwrap()]
+        ));
+    }
+}
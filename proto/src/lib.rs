@@ -0,0 +1,471 @@
+//! Messages exchanged between `cbak` and `cbakd` over the local socket.
+//!
+//! Every message is a single line of JSON. A client opens the connection with a [`Request::Hello`]
+//! carrying its [`PROTOCOL_VERSION`], the daemon answers with its own [`Response::Hello`] or an
+//! [`ErrorKind::VersionMismatch`] error. After that requests & responses alternate until the client hangs up.
+//! The only exception is [`Response::FileContent`], which is followed by the raw bytes of a file.
+
+use std::{
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read},
+};
+
+use cbak_config::{_DirConfig, ConfigProblem};
+use interprocess::local_socket::{LocalSocketStream, NameTypeSupport};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Bumped whenever a change to the messages would break an older peer
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Name of the socket cbakd listens on
+pub fn socket_name() -> &'static str {
+    use NameTypeSupport::*;
+    match NameTypeSupport::query() {
+        OnlyPaths => "/tmp/cbak.sock",
+        OnlyNamespaced | Both => "@cbak.sock",
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Hello {
+        version: u32,
+    },
+    /// Re-read the configuration & restart the workers
+    Reload,
+    /// Absolute path of the configuration file in use
+    ConfigPath,
+    /// Every problem in the configuration file as it is on disk now, which may differ from the one in use
+    CheckConfig,
+    /// Last error of every watch
+    Errors,
+    /// Health of every worker
+    Status,
+    /// Name & directory of every configured watch
+    ListWatches,
+    /// Appends a `[[watch]]` block to the config & reloads.
+    /// `directory` has to be absolute, the daemon doesn't share the client's working directory
+    AddWatch {
+        watch: _DirConfig,
+    },
+    /// Drops the `[[watch]]` block with this name & reloads
+    RemoveWatch {
+        name: String,
+    },
+    /// A key of the `global` section or of a watch, the whole section if `key` is `None`
+    GetKey {
+        scope: String,
+        key: Option<String>,
+    },
+    /// Sets a key of the `global` section or of a watch, `value: None` unsets it
+    SetKey {
+        scope: String,
+        key: String,
+        value: Option<Vec<String>>,
+        reload: bool,
+    },
+    /// Commits of a watch, newest first. `path` is relative to the watched directory or absolute,
+    /// `since` & `until` are times as understood by cbakd, e.g. `2022-10-20 14:00`
+    Log {
+        name: String,
+        path: Option<String>,
+        since: Option<String>,
+        until: Option<String>,
+        limit: Option<usize>,
+    },
+    /// Unified diff of a watch between `from` & `to`, each a revision or a time.
+    /// `from` defaults to the last commit, `to` to the files on disk
+    Diff {
+        name: String,
+        from: Option<String>,
+        to: Option<String>,
+        path: Option<String>,
+    },
+    /// Puts `paths` (everything if empty) back the way they were at `rev`, a revision or a time,
+    /// & commits the result. A dry run only reports what would change
+    Restore {
+        name: String,
+        rev: String,
+        paths: Vec<String>,
+        dry_run: bool,
+    },
+    /// Stop committing changes of a watch, for `seconds` or until resumed
+    Pause {
+        name: String,
+        seconds: Option<u64>,
+    },
+    /// Continue committing, with one commit for everything that changed while paused if `commit` is set
+    Resume {
+        name: String,
+        commit: bool,
+    },
+    /// Commits everything in a watch right away with `message`. `author` is `Name <email>`,
+    /// cbakd's own identity is used without it
+    Commit {
+        name: String,
+        message: String,
+        author: Option<String>,
+    },
+    /// Commits pending changes & creates an annotated tag `tag` on the result.
+    /// The tag name is used as message without `message`
+    Tag {
+        name: String,
+        tag: String,
+        message: Option<String>,
+    },
+    /// Tags of a watch, newest first
+    Tags {
+        name: String,
+    },
+    /// Contents of a file at `rev`, a revision or a time. Answered with [`Response::FileContent`]
+    Show {
+        name: String,
+        rev: String,
+        path: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello {
+        version: u32,
+    },
+    Ok,
+    ConfigPath {
+        path: String,
+    },
+    /// Problems found in the config file at `path`, empty if there are none
+    ConfigCheck {
+        path: String,
+        problems: Vec<ConfigProblem>,
+    },
+    Errors {
+        watches: Vec<WatchError>,
+    },
+    Status {
+        watches: Vec<WatchStatus>,
+    },
+    Watches {
+        watches: Vec<WatchInfo>,
+    },
+    /// TOML text of the requested key or section, `None` if the key isn't set
+    Value {
+        value: Option<String>,
+    },
+    Log {
+        commits: Vec<CommitInfo>,
+    },
+    Diff {
+        patch: String,
+    },
+    Restored {
+        /// Commit the files were taken from
+        from: String,
+        /// `added` files are brought back, `deleted` ones removed
+        files: Vec<ChangedFile>,
+        /// The commit recording the restore, `None` for a dry run or if nothing changed
+        commit: Option<String>,
+    },
+    /// The new commit, `None` if nothing had changed
+    Committed {
+        commit: Option<String>,
+    },
+    /// The commit that was tagged
+    Tagged {
+        commit: String,
+    },
+    Tags {
+        tags: Vec<TagInfo>,
+    },
+    /// Followed by `size` bytes of raw file content, read them with [`Client::read_content`]
+    FileContent {
+        size: u64,
+    },
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchError {
+    pub name: String,
+    /// Local time the error happened, `None` if the watch is healthy
+    pub time: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Times are in RFC 3339
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchStatus {
+    pub name: String,
+    pub directory: String,
+    pub state: WorkerState,
+    /// When a timed pause ends
+    pub paused_until: Option<String>,
+    /// When changes were last detected
+    pub last_change: Option<String>,
+    pub last_commit: Option<CommitRef>,
+    /// Changes detected but not committed yet
+    pub pending: usize,
+    pub last_error: Option<String>,
+    pub last_error_time: Option<String>,
+    /// Times a failed worker was restarted
+    pub restarts: u32,
+    /// When a failed worker is started again
+    pub next_restart: Option<String>,
+}
+
+/// An annotated or lightweight tag, `time` is when it was made in RFC 3339
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TagInfo {
+    pub name: String,
+    pub commit: String,
+    pub time: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommitRef {
+    pub id: String,
+    pub time: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    Running,
+    /// Not committing until resumed
+    Paused,
+    /// The worker gave up, see the last error
+    Failed,
+}
+
+impl Display for WorkerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WorkerState::Running => "running",
+            WorkerState::Paused => "paused",
+            WorkerState::Failed => "failed",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchInfo {
+    pub name: String,
+    pub directory: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub id: String,
+    /// Commit time in RFC 3339
+    pub time: String,
+    pub author: String,
+    pub message: String,
+    pub files: Vec<ChangedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangedFile {
+    pub change: ChangeKind,
+    /// Relative to the watched directory
+    pub path: String,
+    /// Where a renamed file was before
+    pub old_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    VersionMismatch,
+    /// The request couldn't be parsed or wasn't expected at this point
+    BadRequest,
+    NotFound,
+    /// A config edit was rejected, the file was left untouched
+    InvalidConfig,
+    /// Only users who may write the config file can change anything or read committed files
+    PermissionDenied,
+    Internal,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorKind::VersionMismatch => "version mismatch",
+            ErrorKind::BadRequest => "bad request",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::InvalidConfig => "invalid config",
+            ErrorKind::PermissionDenied => "permission denied",
+            ErrorKind::Internal => "internal error",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtoError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The daemon answered with an error
+    Remote {
+        kind: ErrorKind,
+        message: String,
+    },
+    /// The daemon answered with a response that doesn't fit the request
+    Unexpected(Response),
+}
+
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtoError::Io(e) => write!(f, "socket error: {}", e),
+            ProtoError::Json(e) => write!(f, "malformed message: {}", e),
+            ProtoError::Remote { kind, message } => write!(f, "{}: {}", kind, message),
+            ProtoError::Unexpected(r) => write!(f, "unexpected response: {:?}", r),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+impl From<io::Error> for ProtoError {
+    fn from(e: io::Error) -> Self {
+        ProtoError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ProtoError {
+    fn from(e: serde_json::Error) -> Self {
+        ProtoError::Json(e)
+    }
+}
+
+/// Longest request line cbakd reads, requests are small & anything longer is a broken or hostile client
+pub const MAX_REQUEST_LINE: u64 = 64 * 1024;
+
+/// A newline delimited JSON stream
+pub struct Connection<S: io::Read + io::Write> {
+    stream: BufReader<S>,
+    /// Longest line `recv` accepts
+    max_line: u64,
+}
+
+impl<S: io::Read + io::Write> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            max_line: u64::MAX,
+        }
+    }
+
+    /// Makes `recv` fail on lines longer than `max` bytes instead of reading them into memory
+    pub fn with_max_line(mut self, max: u64) -> Self {
+        self.max_line = max;
+        self
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+
+    pub fn send<T: Serialize>(&mut self, msg: &T) -> Result<(), ProtoError> {
+        let mut line = serde_json::to_vec(msg)?;
+        line.push(b'\n');
+        self.stream.get_mut().write_all(&line)?;
+        self.stream.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// Sends bytes as they are, for payloads announced by the previous message
+    pub fn send_raw(&mut self, data: &[u8]) -> Result<(), ProtoError> {
+        self.stream.get_mut().write_all(data)?;
+        self.stream.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// Copies exactly `size` raw bytes to `out`
+    pub fn recv_raw(&mut self, size: u64, out: &mut impl io::Write) -> Result<(), ProtoError> {
+        let copied = io::copy(&mut (&mut self.stream).take(size), out)?;
+        if copied < size {
+            return Err(ProtoError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "daemon closed the connection",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads the next message, `Ok(None)` if the peer closed the connection
+    pub fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>, ProtoError> {
+        let mut line = String::new();
+        let read = (&mut self.stream)
+            .take(self.max_line.saturating_add(1))
+            .read_line(&mut line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if read as u64 > self.max_line {
+            return Err(ProtoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message longer than {} bytes", self.max_line),
+            )));
+        }
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+}
+
+/// Client side of a connection to cbakd, the handshake is done on connect
+pub struct Client {
+    conn: Connection<LocalSocketStream>,
+}
+
+impl Client {
+    pub fn connect(name: &str) -> Result<Self, ProtoError> {
+        let mut client = Self {
+            conn: Connection::new(LocalSocketStream::connect(name)?),
+        };
+        match client.request(&Request::Hello {
+            version: PROTOCOL_VERSION,
+        })? {
+            Response::Hello { version } if version == PROTOCOL_VERSION => Ok(client),
+            Response::Hello { version } => Err(ProtoError::Remote {
+                kind: ErrorKind::VersionMismatch,
+                message: format!(
+                    "daemon speaks protocol version {}, client {}",
+                    version, PROTOCOL_VERSION
+                ),
+            }),
+            r => Err(ProtoError::Unexpected(r)),
+        }
+    }
+
+    /// Copies the payload following a [`Response::FileContent`] to `out`
+    pub fn read_content(&mut self, size: u64, out: &mut impl io::Write) -> Result<(), ProtoError> {
+        self.conn.recv_raw(size, out)
+    }
+
+    /// Sends a request & waits for the answer. Error responses are returned as `ProtoError::Remote`
+    pub fn request(&mut self, req: &Request) -> Result<Response, ProtoError> {
+        self.conn.send(req)?;
+        match self.conn.recv::<Response>()? {
+            Some(Response::Error { kind, message }) => Err(ProtoError::Remote { kind, message }),
+            Some(r) => Ok(r),
+            None => Err(ProtoError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "daemon closed the connection",
+            ))),
+        }
+    }
+}